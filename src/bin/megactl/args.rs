@@ -34,6 +34,10 @@ pub enum Commands {
     ListChannels(ListChannelsArgs),
     /// Terminate and remove a channel
     DisposeChannel(DisposeChannelArgs),
+    /// List webhook calls waiting in the outbox
+    ListOutbox,
+    /// Remove webhook calls from the outbox
+    PurgeOutbox(PurgeOutboxArgs),
 }
 
 #[derive(Args, Debug)]
@@ -78,3 +82,13 @@ pub struct DisposeChannelArgs {
     #[arg(short, long)]
     pub name: String,
 }
+
+#[derive(Args, Debug)]
+pub struct PurgeOutboxArgs {
+    /// Only purge the calls of this webhook
+    #[arg(short, long, conflicts_with = "id")]
+    pub webhook: Option<String>,
+    /// Only purge this call
+    #[arg(short, long)]
+    pub id: Option<String>,
+}
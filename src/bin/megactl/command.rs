@@ -6,6 +6,7 @@ use serde_json::json;
 use megaphone::dto::agent::{BasicOutcomeDto, VirtualAgentItemDto};
 
 use crate::args::OutFormat;
+use crate::dto::{OutboxEntryDto, OutboxPurgeResDto};
 
 pub async fn execute_command<Cmd, FutRes, Res>(out_format: OutFormat, command: Cmd)
 where
@@ -73,6 +74,27 @@ impl PrintFormat<PlainFormat> for Vec<VirtualAgentItemDto> {
     }
 }
 
+impl PrintFormat<PlainFormat> for Vec<OutboxEntryDto> {
+    fn print(&self) {
+        println!(
+            "{0: <33} | {1: <16} | {2: <33} | {3: <10}",
+            "ID", "WEBHOOK", "CREATED", "ENDPOINT"
+        );
+        for item in self {
+            println!(
+                "{0: <33} | {1: <16} | {2: <33} | {3: <10}",
+                item.id, item.webhook, item.created_at, item.endpoint
+            );
+        }
+    }
+}
+
+impl PrintFormat<PlainFormat> for OutboxPurgeResDto {
+    fn print(&self) {
+        println!("Purged {} webhook call(s)", self.purged);
+    }
+}
+
 impl PrintFormat<PlainFormat> for BasicOutcomeDto {
     fn print(&self) {
         println!("Operation completed successfully");
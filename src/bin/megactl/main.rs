@@ -1,6 +1,7 @@
 use clap::Parser;
 use hyper::Client;
 use hyperlocal::{UnixClientExt, Uri};
+use reqwest::Url;
 
 use megaphone::dto::agent::{
     AddVirtualAgentReqDto, BasicOutcomeDto, PipeVirtualAgentReqDto, VirtualAgentItemDto,
@@ -9,10 +10,13 @@ use megaphone::dto::agent::{
 use crate::args::{Commands, PluCtlArgs};
 use crate::client::SimpleRest;
 use crate::command::execute_command;
+use crate::dto::{OutboxEntryDto, OutboxPurgeParams, OutboxPurgeResDto};
 
 mod args;
 mod client;
 mod command;
+#[path = "../../dto/webhook.rs"]
+mod dto;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
@@ -59,6 +63,34 @@ async fn main() -> anyhow::Result<()> {
             })
             .await;
         }
+        Commands::ListOutbox => {
+            execute_command(args.out_format, || {
+                client.get::<_, Vec<OutboxEntryDto>>(Uri::new(args.path, "/webhook/outbox"))
+            })
+            .await;
+        }
+        Commands::PurgeOutbox(purge_outbox_args) => {
+            let path = match (purge_outbox_args.id, purge_outbox_args.webhook) {
+                (Some(id), _) => format!("/webhook/outbox/{id}"),
+                (None, webhook) => outbox_purge_path(&OutboxPurgeParams { webhook }),
+            };
+            execute_command(args.out_format, || {
+                client.delete::<_, OutboxPurgeResDto>(Uri::new(args.path, &path))
+            })
+            .await;
+        }
     }
     Ok(())
 }
+
+/// Path of the outbox purge, with the query parameters url encoded
+fn outbox_purge_path(params: &OutboxPurgeParams) -> String {
+    let mut url = Url::parse("unix:/webhook/outbox").expect("Invalid outbox url");
+    if let Some(webhook) = &params.webhook {
+        url.query_pairs_mut().append_pair("webhook", webhook);
+    }
+    match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => String::from(url.path()),
+    }
+}
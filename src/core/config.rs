@@ -1,14 +1,17 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::fmt;
 use std::marker::PhantomData;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use config::{Config, ConfigError, Environment, File};
 use serde::de::{MapAccess, Visitor};
-use serde::{de, Deserialize, Deserializer};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::core::label_selector::LabelSelector;
 
 pub fn compose_config<'de, CFG: Deserialize<'de>>(
     external_path: &str,
@@ -39,6 +42,62 @@ pub struct MegaphoneConfig {
     pub poll_duration_millis: u64,
     #[serde(default)]
     pub webhooks: HashMap<String, WebHook>,
+    #[serde(default = "default_consumer_disconnect_grace_millis")]
+    pub consumer_disconnect_grace_millis: u64,
+    #[serde(default = "default_webhook_timeout_millis")]
+    pub webhook_timeout_millis: u64,
+    #[serde(default = "default_webhook_max_retries")]
+    pub webhook_max_retries: u32,
+    #[serde(default = "default_webhook_retry_backoff_millis")]
+    pub webhook_retry_backoff_millis: u64,
+    pub webhook_outbox_path: Option<PathBuf>,
+    #[serde(default)]
+    pub message_endpoint_allowlist: Vec<String>,
+    #[serde(default = "default_channel_buffer_size")]
+    pub channel_buffer_size: usize,
+    #[serde(default)]
+    pub channel_overflow_policy: OverflowPolicy,
+    #[serde(default = "default_channel_write_timeout_secs")]
+    pub channel_write_timeout_secs: u64,
+    #[serde(default = "default_channel_ttl_secs")]
+    pub channel_ttl_secs: u64,
+    #[serde(default = "default_event_ttl_secs")]
+    pub event_ttl_secs: u64,
+    #[serde(default = "default_cleanup_interval_secs")]
+    pub cleanup_interval_secs: u64,
+    #[serde(default = "default_channel_replay_size")]
+    pub channel_replay_size: usize,
+    #[serde(default)]
+    pub channel_consumer_mode: ConsumerMode,
+    #[serde(default)]
+    pub channel_ack_mode: bool,
+    #[serde(default = "default_channel_visibility_timeout_secs")]
+    pub channel_visibility_timeout_secs: u64,
+    #[serde(default = "default_channel_max_bytes")]
+    pub channel_max_bytes: usize,
+    #[serde(default = "default_max_message_size_bytes")]
+    pub max_message_size_bytes: usize,
+    #[serde(default = "default_sse_keep_alive_secs")]
+    pub sse_keep_alive_secs: u64,
+    #[serde(default = "default_sse_retry_millis")]
+    pub sse_retry_millis: u64,
+    #[serde(default = "default_ws_ping_interval_secs")]
+    pub ws_ping_interval_secs: u64,
+    #[serde(default)]
+    pub storage_mode: StorageMode,
+    #[serde(default = "default_storage_path")]
+    pub storage_path: PathBuf,
+    #[serde(default)]
+    pub storage_fsync: bool,
+    #[serde(default = "default_storage_compaction_threshold_bytes")]
+    pub storage_compaction_threshold_bytes: u64,
+    #[serde(default = "default_idempotency_window_secs")]
+    pub idempotency_window_secs: u64,
+    #[serde(default)]
+    pub sync_compression: bool,
+    pub shutdown_handoff_target: Option<String>,
+    #[serde(default = "default_shutdown_drain_timeout_secs")]
+    pub shutdown_drain_timeout_secs: u64,
 }
 
 fn default_agent_warmup_secs() -> u64 {
@@ -60,16 +119,162 @@ fn default_poll_duration() -> u64 {
     20_000
 }
 
+fn default_consumer_disconnect_grace_millis() -> u64 {
+    5_000
+}
+
+fn default_webhook_timeout_millis() -> u64 {
+    5_000
+}
+
+fn default_webhook_max_retries() -> u32 {
+    5
+}
+
+fn default_webhook_retry_backoff_millis() -> u64 {
+    500
+}
+
+fn default_channel_buffer_size() -> usize {
+    100
+}
+
+fn default_channel_write_timeout_secs() -> u64 {
+    10
+}
+
+fn default_channel_ttl_secs() -> u64 {
+    60
+}
+
+fn default_event_ttl_secs() -> u64 {
+    60
+}
+
+fn default_cleanup_interval_secs() -> u64 {
+    10
+}
+
+fn default_channel_replay_size() -> usize {
+    100
+}
+
+fn default_channel_visibility_timeout_secs() -> u64 {
+    30
+}
+
+fn default_channel_max_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_max_message_size_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_sse_keep_alive_secs() -> u64 {
+    15
+}
+
+fn default_sse_retry_millis() -> u64 {
+    1_000
+}
+
+fn default_ws_ping_interval_secs() -> u64 {
+    15
+}
+
+fn default_storage_path() -> PathBuf {
+    PathBuf::from("/var/lib/megaphone")
+}
+
+fn default_storage_compaction_threshold_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_idempotency_window_secs() -> u64 {
+    300
+}
+
+fn default_shutdown_drain_timeout_secs() -> u64 {
+    30
+}
+
+/// Behaviour of a channel when a message is written and its buffer is already full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Refuse the message straight away
+    Reject,
+    /// Wait for the consumer to free a slot, up to the channel write timeout
+    #[default]
+    Block,
+    /// Discard the oldest buffered message to make room for the new one
+    DropOldest,
+    /// Discard the message being written
+    DropNewest,
+}
+
+/// How a channel is shared among the consumers reading from its consumer address
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConsumerMode {
+    /// A single consumer at a time, concurrent reads are refused
+    #[default]
+    Exclusive,
+    /// Every consumer receives all the events through its own cursor
+    FanOut,
+}
+
+/// Where the channels state is kept
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageMode {
+    /// Channels live in memory only and are lost on restart
+    #[default]
+    Memory,
+    /// Channels changes are appended to a write-ahead log and replayed on startup
+    Wal,
+}
+
 #[derive(Clone, Deserialize)]
 pub struct WebHook {
     pub hook: WebHookType,
     pub endpoint: String,
+    /// Only channels of this virtual agent
+    pub agent: Option<String>,
+    /// Only channels whose labels match the selector
+    #[serde(default)]
+    pub selector: LabelSelector,
+    /// Key of the HMAC-SHA256 signature sent with each call
+    pub secret: Option<String>,
+    /// Calls happening within the window are sent together
+    #[serde(default)]
+    pub batch_window_millis: u64,
+}
+
+impl WebHook {
+    pub fn batch_window(&self) -> Option<Duration> {
+        (self.batch_window_millis > 0).then(|| Duration::from_millis(self.batch_window_millis))
+    }
+
+    pub fn accepts(&self, agent: &str, labels: &BTreeMap<String, String>) -> bool {
+        self.agent.as_deref().is_none_or(|name| name == agent) && self.selector.matches(labels)
+    }
 }
 
-#[derive(Clone, Copy, Deserialize)]
+// Variants are named after the hooks in the config
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum WebHookType {
     OnChannelDeleted,
+    OnConsumerMessage,
+    OnConsumerConnected,
+    OnConsumerDisconnected,
+    OnChannelCreated,
+    OnMessagesLost,
+    OnBufferFull,
+    OnWriteTimeout,
 }
 #[derive(Clone, Deserialize)]
 pub struct AgentConfig {
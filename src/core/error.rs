@@ -1,6 +1,7 @@
 use axum::http::StatusCode;
 use axum::Json;
 use thiserror::Error;
+use tonic::{Code, Status};
 
 use megaphone::dto::error::ErrorDto;
 
@@ -18,6 +19,14 @@ pub enum MegaphoneError {
     Timeout { secs: usize },
     #[error("Skipped")]
     Skipped,
+    #[error("Buffer is full")]
+    BufferFull,
+    #[error("Server is shutting down")]
+    ShuttingDown,
+    #[error("Message of {size} bytes exceeds the limit of {max} bytes")]
+    MessageTooLarge { size: usize, max: usize },
+    #[error("Channel is closed")]
+    ChannelClosed,
 }
 
 impl MegaphoneError {
@@ -29,6 +38,10 @@ impl MegaphoneError {
             MegaphoneError::BadRequest(_) => "BAD_REQUEST",
             MegaphoneError::Timeout { .. } => "TIMEOUT",
             MegaphoneError::Skipped => "SKIPPED",
+            MegaphoneError::BufferFull => "BUFFER_FULL",
+            MegaphoneError::ShuttingDown => "SHUTTING_DOWN",
+            MegaphoneError::MessageTooLarge { .. } => "MESSAGE_TOO_LARGE",
+            MegaphoneError::ChannelClosed => "CHANNEL_CLOSED",
         }
     }
 }
@@ -78,6 +91,52 @@ impl From<MegaphoneError> for (StatusCode, Json<ErrorDto>) {
                     message: String::from("Skipped"),
                 }),
             ),
+            MegaphoneError::BufferFull => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ErrorDto {
+                    code: String::from(err.code()),
+                    message: String::from("Buffer is full"),
+                }),
+            ),
+            MegaphoneError::ShuttingDown => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ErrorDto {
+                    code: String::from(err.code()),
+                    message: String::from("Server is shutting down"),
+                }),
+            ),
+            MegaphoneError::MessageTooLarge { .. } => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(ErrorDto {
+                    code: String::from(err.code()),
+                    message: err.to_string(),
+                }),
+            ),
+            MegaphoneError::ChannelClosed => (
+                StatusCode::GONE,
+                Json(ErrorDto {
+                    code: String::from(err.code()),
+                    message: String::from("Channel is closed"),
+                }),
+            ),
         }
     }
 }
+
+impl From<MegaphoneError> for Status {
+    fn from(err: MegaphoneError) -> Self {
+        let code = match &err {
+            MegaphoneError::NotFound => Code::NotFound,
+            MegaphoneError::Busy => Code::FailedPrecondition,
+            MegaphoneError::InternalError(_) => Code::Internal,
+            MegaphoneError::BadRequest(_) => Code::InvalidArgument,
+            MegaphoneError::Timeout { .. } => Code::DeadlineExceeded,
+            MegaphoneError::Skipped => Code::Unavailable,
+            MegaphoneError::BufferFull => Code::ResourceExhausted,
+            MegaphoneError::ShuttingDown => Code::Unavailable,
+            MegaphoneError::MessageTooLarge { .. } => Code::InvalidArgument,
+            MegaphoneError::ChannelClosed => Code::FailedPrecondition,
+        };
+        Status::new(code, format!("{} - {err}", err.code()))
+    }
+}
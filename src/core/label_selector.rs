@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::core::error::MegaphoneError;
+
+/// Condition on a single label
+#[derive(Clone, Debug)]
+enum LabelRequirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+}
+
+impl LabelRequirement {
+    fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        match self {
+            Self::Equals(key, value) => labels.get(key) == Some(value),
+            Self::NotEquals(key, value) => labels.get(key) != Some(value),
+            Self::Exists(key) => labels.contains_key(key),
+            Self::NotExists(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+impl FromStr for LabelRequirement {
+    type Err = MegaphoneError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let requirement = if let Some((key, value)) = s.split_once("!=") {
+            Self::NotEquals(String::from(key.trim()), String::from(value.trim()))
+        } else if let Some((key, value)) = s.split_once('=') {
+            let value = value.strip_prefix('=').unwrap_or(value);
+            Self::Equals(String::from(key.trim()), String::from(value.trim()))
+        } else if let Some(key) = s.strip_prefix('!') {
+            Self::NotExists(String::from(key.trim()))
+        } else {
+            Self::Exists(String::from(s))
+        };
+        match &requirement {
+            Self::Equals(key, _)
+            | Self::NotEquals(key, _)
+            | Self::Exists(key)
+            | Self::NotExists(key)
+                if key.is_empty() =>
+            {
+                Err(MegaphoneError::BadRequest(format!(
+                    "Malformed label requirement '{s}'"
+                )))
+            }
+            _ => Ok(requirement),
+        }
+    }
+}
+
+/// Comma separated list of label requirements (`key=value`, `key!=value`, `key` or `!key`),
+/// an empty selector matches every channel
+#[derive(Clone, Debug, Default)]
+pub struct LabelSelector {
+    requirements: Vec<LabelRequirement>,
+}
+
+impl LabelSelector {
+    pub fn is_empty(&self) -> bool {
+        self.requirements.is_empty()
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter().all(|req| req.matches(labels))
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = MegaphoneError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let requirements = s
+            .split(',')
+            .map(str::trim)
+            .filter(|requirement| !requirement.is_empty())
+            .map(LabelRequirement::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { requirements })
+    }
+}
+
+impl<'de> Deserialize<'de> for LabelSelector {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (String::from(*key), String::from(*value)))
+            .collect()
+    }
+
+    #[test]
+    fn empty_selector_matches_every_channel() {
+        let selector = LabelSelector::from_str(" , ").unwrap();
+        assert!(selector.is_empty());
+        assert!(selector.matches(&labels(&[])));
+    }
+
+    #[test]
+    fn every_requirement_must_match() {
+        let selector = LabelSelector::from_str("tier=gold, region != eu, beta, !legacy").unwrap();
+        assert!(selector.matches(&labels(&[("tier", "gold"), ("beta", "")])));
+        assert!(!selector.matches(&labels(&[("tier", "gold"), ("beta", ""), ("region", "eu")])));
+        assert!(!selector.matches(&labels(&[("tier", "gold"), ("beta", ""), ("legacy", "1")])));
+        assert!(!selector.matches(&labels(&[("tier", "gold")])));
+    }
+
+    #[test]
+    fn double_equals_is_an_equality() {
+        let selector = LabelSelector::from_str("tier==gold").unwrap();
+        assert!(selector.matches(&labels(&[("tier", "gold")])));
+        assert!(!selector.matches(&labels(&[("tier", "silver")])));
+    }
+
+    #[test]
+    fn requirements_without_key_are_rejected() {
+        for selector in ["=gold", "!=gold", "!"] {
+            assert!(
+                matches!(
+                    LabelSelector::from_str(selector),
+                    Err(MegaphoneError::BadRequest(_))
+                ),
+                "{selector}"
+            );
+        }
+    }
+}
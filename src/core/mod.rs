@@ -1,2 +1,5 @@
 pub mod config;
 pub mod error;
+pub mod label_selector;
+pub mod protocols;
+pub mod stream_filter;
@@ -0,0 +1,49 @@
+pub use megaphone::model::constants::protocols::HTTP_STREAM_NDJSON_V1;
+
+pub const HTTP_SSE_V1: &str = "http-sse-v1";
+pub const WEBSOCKET_V1: &str = "websocket-v1";
+pub const HTTP_LONG_POLL_V1: &str = "http-long-poll-v1";
+
+pub const SUPPORTED_PROTOCOLS: &[&str] = &[
+    HTTP_STREAM_NDJSON_V1,
+    HTTP_SSE_V1,
+    WEBSOCKET_V1,
+    HTTP_LONG_POLL_V1,
+];
+
+/// Select the requested protocols supported by this server. Clients not requesting any protocol
+/// predate the negotiation and only speak ndjson streaming.
+pub fn negotiate(requested: &[String]) -> Vec<String> {
+    if requested.is_empty() {
+        vec![String::from(HTTP_STREAM_NDJSON_V1)]
+    } else {
+        requested
+            .iter()
+            .filter(|p| SUPPORTED_PROTOCOLS.contains(&p.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_requested_protocol_defaults_to_ndjson() {
+        assert_eq!(negotiate(&[]), vec![String::from(HTTP_STREAM_NDJSON_V1)]);
+    }
+
+    #[test]
+    fn keeps_supported_protocols_in_requested_order() {
+        let requested = [
+            String::from(WEBSOCKET_V1),
+            String::from("carrier-pigeon-v1"),
+            String::from(HTTP_SSE_V1),
+        ];
+        assert_eq!(
+            negotiate(&requested),
+            vec![String::from(WEBSOCKET_V1), String::from(HTTP_SSE_V1)]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use regex::Regex;
+
+/// Pattern matching a stream id, either exactly or with `*` and `?` wildcards
+#[derive(Clone, Debug)]
+enum StreamPattern {
+    Exact(String),
+    Glob(Regex),
+}
+
+impl StreamPattern {
+    fn new(pattern: &str) -> Self {
+        if !pattern.contains(['*', '?']) {
+            return Self::Exact(String::from(pattern));
+        }
+        let expr = pattern
+            .split_inclusive(['*', '?'])
+            .map(|chunk| match chunk.strip_suffix('*') {
+                Some(prefix) => format!("{}.*", regex::escape(prefix)),
+                None => match chunk.strip_suffix('?') {
+                    Some(prefix) => format!("{}.", regex::escape(prefix)),
+                    None => regex::escape(chunk),
+                },
+            })
+            .collect::<String>();
+        Self::Glob(Regex::new(&format!("^{expr}$")).expect("escaped glob must be a valid regex"))
+    }
+
+    fn matches(&self, stream_id: &str) -> bool {
+        match self {
+            Self::Exact(pattern) => pattern == stream_id,
+            Self::Glob(re) => re.is_match(stream_id),
+        }
+    }
+}
+
+/// Set of stream id patterns, an empty filter matches every stream
+#[derive(Clone, Debug, Default)]
+pub struct StreamFilter {
+    patterns: Vec<StreamPattern>,
+}
+
+impl StreamFilter {
+    pub fn new(patterns: &[String]) -> Self {
+        Self {
+            patterns: patterns
+                .iter()
+                .map(|pattern| StreamPattern::new(pattern))
+                .collect(),
+        }
+    }
+
+    pub fn matches(&self, stream_id: &str) -> bool {
+        self.patterns.is_empty() || self.patterns.iter().any(|p| p.matches(stream_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(patterns: &[&str]) -> StreamFilter {
+        StreamFilter::new(
+            &patterns
+                .iter()
+                .map(|p| String::from(*p))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn empty_filter_matches_every_stream() {
+        assert!(filter(&[]).matches("anything"));
+    }
+
+    #[test]
+    fn exact_patterns_match_the_whole_id() {
+        let filter = filter(&["orders"]);
+        assert!(filter.matches("orders"));
+        assert!(!filter.matches("orders.created"));
+    }
+
+    #[test]
+    fn wildcards_match_any_characters() {
+        let filter = filter(&["orders.*", "user-?"]);
+        assert!(filter.matches("orders.created"));
+        assert!(filter.matches("orders."));
+        assert!(filter.matches("user-1"));
+        assert!(!filter.matches("user-12"));
+        assert!(!filter.matches("payments.created"));
+    }
+
+    #[test]
+    fn regex_characters_are_literal() {
+        let filter = filter(&["a.b*", "(c)?"]);
+        assert!(filter.matches("a.bc"));
+        assert!(!filter.matches("axbc"));
+        assert!(filter.matches("(c)1"));
+        assert!(!filter.matches("c1"));
+    }
+}
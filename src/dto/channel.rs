@@ -0,0 +1,324 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::core::config::{ConsumerMode, OverflowPolicy};
+use crate::dto::message::{BinaryPayloadDto, EventDto};
+use crate::service::channel_log::ChannelMessage;
+use crate::service::megaphone_service::{ChannelOptions, Labels};
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelCreateReqDto {
+    #[serde(flatten)]
+    pub base: megaphone::dto::channel::ChannelCreateReqDto,
+    pub buffer_size: Option<usize>,
+    pub overflow_policy: Option<OverflowPolicyDto>,
+    pub write_timeout_secs: Option<u64>,
+    pub channel_ttl_secs: Option<u64>,
+    pub event_ttl_secs: Option<u64>,
+    pub replay_size: Option<usize>,
+    pub consumer_mode: Option<ConsumerModeDto>,
+    pub message_endpoint: Option<String>,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default)]
+    pub streams: Vec<String>,
+    pub ack_mode: Option<bool>,
+    pub visibility_timeout_secs: Option<u64>,
+    pub max_bytes: Option<usize>,
+    #[serde(default)]
+    pub labels: Labels,
+}
+
+impl ChannelCreateReqDto {
+    pub fn channel_options(&self, defaults: ChannelOptions) -> ChannelOptions {
+        ChannelOptions {
+            buffer_size: self.buffer_size.unwrap_or(defaults.buffer_size),
+            overflow_policy: self
+                .overflow_policy
+                .map(OverflowPolicy::from)
+                .unwrap_or(defaults.overflow_policy),
+            write_timeout: self
+                .write_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.write_timeout),
+            channel_ttl: self
+                .channel_ttl_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.channel_ttl),
+            event_ttl: self
+                .event_ttl_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.event_ttl),
+            replay_size: self.replay_size.unwrap_or(defaults.replay_size),
+            consumer_mode: self
+                .consumer_mode
+                .map(ConsumerMode::from)
+                .unwrap_or(defaults.consumer_mode),
+            message_endpoint: self.message_endpoint.clone().or(defaults.message_endpoint),
+            streams: if self.streams.is_empty() {
+                defaults.streams
+            } else {
+                self.streams.clone()
+            },
+            ack_mode: self.ack_mode.unwrap_or(defaults.ack_mode),
+            visibility_timeout: self
+                .visibility_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.visibility_timeout),
+            max_bytes: self.max_bytes.unwrap_or(defaults.max_bytes),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelCreateResDto {
+    #[serde(flatten)]
+    pub base: megaphone::dto::channel::ChannelCreateResDto,
+    pub buffer_size: usize,
+    pub overflow_policy: OverflowPolicyDto,
+    pub write_timeout_secs: u64,
+    pub channel_ttl_secs: u64,
+    pub event_ttl_secs: u64,
+    pub replay_size: usize,
+    pub consumer_mode: ConsumerModeDto,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_endpoint: Option<String>,
+    pub topics: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub streams: Vec<String>,
+    pub ack_mode: bool,
+    pub visibility_timeout_secs: u64,
+    pub max_bytes: usize,
+    pub labels: Labels,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelInfoDto {
+    #[serde(flatten)]
+    pub base: megaphone::dto::channel::ChannelInfoDto,
+    pub redeliveries: u64,
+    pub bytes: usize,
+    pub labels: Labels,
+}
+
+#[derive(Deserialize)]
+pub struct ChannelsListParams {
+    pub skip: usize,
+    pub limit: usize,
+    /// Label selector, e.g. `tenant=acme,user!=42`
+    pub selector: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AckReqDto {
+    /// Consumer identifier, used by fan-out channels
+    pub consumer: Option<String>,
+    pub event_ids: HashSet<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AckResDto {
+    pub acked: usize,
+}
+
+#[derive(Deserialize)]
+pub struct ReadChannelParams {
+    /// Id of the last event received by the consumer
+    pub after: Option<String>,
+    /// Consumer identifier, used by fan-out channels
+    pub consumer: Option<String>,
+    #[serde(default)]
+    pub mode: ReadModeDto,
+    /// Maximum number of events returned by a poll read
+    pub max: Option<usize>,
+    /// Comma separated list of stream id patterns to deliver
+    pub streams: Option<String>,
+}
+
+impl ReadChannelParams {
+    pub fn stream_patterns(&self) -> Vec<String> {
+        self.streams
+            .iter()
+            .flat_map(|streams| streams.split(','))
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(String::from)
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReadModeDto {
+    /// Events are streamed as they arrive until the poll duration elapses
+    #[default]
+    Stream,
+    /// Wait for at least one event and respond with all the buffered ones
+    Poll,
+}
+
+/// Expiration of a written message
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageExpirationDto {
+    pub ttl_secs: Option<u64>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl MessageExpirationDto {
+    pub fn channel_message(&self, event: EventDto) -> ChannelMessage<EventDto> {
+        ChannelMessage::new(
+            event,
+            self.ttl_secs.map(Duration::from_secs),
+            self.expires_at.map(From::from),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageDto {
+    pub stream_id: String,
+    /// Json payload, ignored for binary messages
+    #[serde(default)]
+    pub body: serde_json::Value,
+    #[serde(flatten)]
+    pub expiration: MessageExpirationDto,
+    pub idempotency_key: Option<String>,
+    /// Raw bytes written instead of the json body, as delivered to consumers
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<BinaryPayloadDto>,
+}
+
+impl From<MessageDto> for ChannelMessage<EventDto> {
+    fn from(value: MessageDto) -> Self {
+        let event = match value.binary {
+            Some(binary) => EventDto::binary(value.stream_id, binary.content_type, binary.data),
+            None => EventDto::new(value.stream_id, value.body),
+        };
+        value
+            .expiration
+            .channel_message(event)
+            .with_idempotency_key(value.idempotency_key)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteBatchReqDto {
+    #[serde(alias = "channelIds")]
+    pub channels: HashSet<String>,
+    pub messages: Vec<MessageDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TopicsReqDto {
+    pub topics: Vec<String>,
+}
+
+/// Message sent by a consumer over a websocket
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsumerMessageDto {
+    pub stream_id: String,
+    pub body: serde_json::Value,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicyDto {
+    Reject,
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+impl From<OverflowPolicyDto> for OverflowPolicy {
+    fn from(value: OverflowPolicyDto) -> Self {
+        match value {
+            OverflowPolicyDto::Reject => Self::Reject,
+            OverflowPolicyDto::Block => Self::Block,
+            OverflowPolicyDto::DropOldest => Self::DropOldest,
+            OverflowPolicyDto::DropNewest => Self::DropNewest,
+        }
+    }
+}
+
+impl From<OverflowPolicy> for OverflowPolicyDto {
+    fn from(value: OverflowPolicy) -> Self {
+        match value {
+            OverflowPolicy::Reject => Self::Reject,
+            OverflowPolicy::Block => Self::Block,
+            OverflowPolicy::DropOldest => Self::DropOldest,
+            OverflowPolicy::DropNewest => Self::DropNewest,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConsumerModeDto {
+    Exclusive,
+    FanOut,
+}
+
+impl From<ConsumerModeDto> for ConsumerMode {
+    fn from(value: ConsumerModeDto) -> Self {
+        match value {
+            ConsumerModeDto::Exclusive => Self::Exclusive,
+            ConsumerModeDto::FanOut => Self::FanOut,
+        }
+    }
+}
+
+impl From<ConsumerMode> for ConsumerModeDto {
+    fn from(value: ConsumerMode) -> Self {
+        match value {
+            ConsumerMode::Exclusive => Self::Exclusive,
+            ConsumerMode::FanOut => Self::FanOut,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_batch_accepts_channel_ids_alias() {
+        let req: WriteBatchReqDto = serde_json::from_str(
+            r#"{"channelIds":["a.b"],"messages":[{"streamId":"s","body":1,"ttlSecs":5,"idempotencyKey":"k"}]}"#,
+        )
+        .unwrap();
+        assert!(req.channels.contains("a.b"));
+        let message = &req.messages[0];
+        assert_eq!(message.stream_id, "s");
+        assert_eq!(message.expiration.ttl_secs, Some(5));
+        assert_eq!(message.idempotency_key.as_deref(), Some("k"));
+    }
+
+    #[test]
+    fn batch_messages_accept_binary_payloads() {
+        let message: MessageDto =
+            serde_json::from_str(r#"{"streamId":"s","contentType":"image/png","data":"AAEC"}"#)
+                .unwrap();
+        let message = ChannelMessage::from(message);
+        let binary = message.event.binary.unwrap();
+        assert_eq!(binary.content_type, "image/png");
+        assert_eq!(&binary.data[..], &[0, 1, 2]);
+        assert_eq!(message.event.body, serde_json::Value::Null);
+
+        let message: MessageDto =
+            serde_json::from_str(r#"{"streamId":"s","body":{"a":1}}"#).unwrap();
+        let message = ChannelMessage::from(message);
+        assert!(message.event.binary.is_none());
+        assert_eq!(message.event.body, serde_json::json!({"a": 1}));
+    }
+}
@@ -0,0 +1,75 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Stream id of the event terminating a channel closed by its producer
+pub const END_OF_STREAM_ID: &str = "$end";
+
+/// Event delivered to consumers, extends the upstream event with binary payloads
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventDto {
+    pub stream_id: String,
+    pub event_id: String,
+    pub timestamp: DateTime<Utc>,
+    /// Json payload, null for binary events
+    pub body: serde_json::Value,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<BinaryPayloadDto>,
+}
+
+impl EventDto {
+    pub fn new(stream_id: String, body: serde_json::Value) -> Self {
+        megaphone::dto::message::EventDto::new(stream_id, body).into()
+    }
+
+    pub fn end_of_stream() -> Self {
+        Self::new(String::from(END_OF_STREAM_ID), serde_json::Value::Null)
+    }
+
+    pub fn binary(stream_id: String, content_type: String, data: Bytes) -> Self {
+        Self {
+            binary: Some(BinaryPayloadDto { content_type, data }),
+            ..Self::new(stream_id, serde_json::Value::Null)
+        }
+    }
+}
+
+impl From<megaphone::dto::message::EventDto> for EventDto {
+    fn from(value: megaphone::dto::message::EventDto) -> Self {
+        Self {
+            stream_id: value.stream_id,
+            event_id: value.event_id,
+            timestamp: value.timestamp,
+            body: value.body,
+            binary: None,
+        }
+    }
+}
+
+/// Raw bytes written with a non-json content type
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BinaryPayloadDto {
+    pub content_type: String,
+    /// Base64 encoded in json
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    pub data: Bytes,
+}
+
+fn serialize_base64<S: Serializer>(data: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(data))
+}
+
+fn deserialize_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    STANDARD
+        .decode(encoded)
+        .map(Bytes::from)
+        .map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,3 @@
+pub mod channel;
+pub mod message;
+pub mod webhook;
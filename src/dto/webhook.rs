@@ -0,0 +1,26 @@
+//! Outbox DTOs, also compiled into `megactl`, so they must not depend on the rest of the crate
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Webhook call pending in the outbox, as listed by `/webhook/outbox`
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntryDto {
+    pub id: String,
+    pub webhook: String,
+    pub endpoint: String,
+    pub created_at: DateTime<Utc>,
+    pub body: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+pub struct OutboxPurgeParams {
+    /// Only purge the entries of this webhook
+    pub webhook: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OutboxPurgeResDto {
+    pub purged: usize,
+}
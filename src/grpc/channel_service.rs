@@ -0,0 +1,261 @@
+use std::pin::Pin;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use prost_types::Timestamp;
+use tonic::{Request, Response, Status};
+
+use crate::core::error::MegaphoneError;
+use crate::dto::message::EventDto;
+use crate::grpc::server::datetime_to_timestamp;
+use crate::grpc::server::megaphone::channel_service_server::ChannelService;
+use crate::grpc::server::megaphone::{
+    AckReply, AckRequest, ChannelEvent, CloseReply, CloseRequest, CreateChannelReply,
+    CreateChannelRequest, DeliveryFailure, ReadRequest, WriteBatchReply, WriteBatchRequest,
+    WriteReply, WriteRequest,
+};
+use crate::grpc::sync_service::timestamp_to_datetime;
+use crate::service::channel_log::ChannelMessage;
+use crate::service::megaphone_service::{ChannelOptions, MegaphoneService, ReadOptions};
+
+pub struct MegaphoneChannelService {
+    megaphone_svc: MegaphoneService<EventDto>,
+}
+
+impl MegaphoneChannelService {
+    pub fn new(megaphone_svc: MegaphoneService<EventDto>) -> Self {
+        Self { megaphone_svc }
+    }
+}
+
+#[tonic::async_trait]
+impl ChannelService for MegaphoneChannelService {
+    async fn create(
+        &self,
+        request: Request<CreateChannelRequest>,
+    ) -> Result<Response<CreateChannelReply>, Status> {
+        let req = request.into_inner();
+        let options = req.channel_options(self.megaphone_svc.default_options());
+        let (agent_name, channel_id, producer_address, protocols) = self
+            .megaphone_svc
+            .create_channel(
+                &req.protocols,
+                options.clone(),
+                &req.topics,
+                req.labels.clone().into_iter().collect(),
+            )
+            .await?;
+
+        Ok(Response::new(CreateChannelReply {
+            consumer_address: channel_id.clone(),
+            channel_id,
+            agent_name,
+            producer_address,
+            protocols,
+            options: Some(options.into()),
+        }))
+    }
+
+    async fn write(&self, request: Request<WriteRequest>) -> Result<Response<WriteReply>, Status> {
+        let req = request.into_inner();
+        let event = parse_event(
+            req.stream_id,
+            &req.json_payload,
+            req.content_type,
+            req.binary_payload,
+        )?;
+        let message = channel_message(event, req.ttl_millis, req.expires_at)
+            .with_idempotency_key(req.idempotency_key);
+        self.megaphone_svc
+            .write_into_channel(&req.producer_address, message)
+            .await?;
+        Ok(Response::new(WriteReply {}))
+    }
+
+    async fn write_batch(
+        &self,
+        request: Request<WriteBatchRequest>,
+    ) -> Result<Response<WriteBatchReply>, Status> {
+        let req = request.into_inner();
+        let messages = req
+            .messages
+            .into_iter()
+            .map(|message| {
+                parse_event(
+                    message.stream_id,
+                    &message.json_payload,
+                    message.content_type,
+                    message.binary_payload,
+                )
+                .map(|event| {
+                    channel_message(event, message.ttl_millis, message.expires_at)
+                        .with_idempotency_key(message.idempotency_key)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let failures = self
+            .megaphone_svc
+            .write_batch_into_channels(&req.producer_addresses, messages)
+            .await
+            .into_iter()
+            .map(|failure| DeliveryFailure {
+                producer_address: failure.channel,
+                index: failure.index as u64,
+                reason: failure.reason,
+            })
+            .collect();
+        Ok(Response::new(WriteBatchReply { failures }))
+    }
+
+    type ReadStream = Pin<Box<dyn Stream<Item = Result<ChannelEvent, Status>> + Send>>;
+
+    async fn read(
+        &self,
+        request: Request<ReadRequest>,
+    ) -> Result<Response<Self::ReadStream>, Status> {
+        let req = request.into_inner();
+        let read_options = ReadOptions {
+            after: req.after,
+            consumer: req.consumer,
+            streams: req.streams,
+        };
+        let stream = self
+            .megaphone_svc
+            .read_channel(
+                req.consumer_address,
+                req.timeout_millis.map(Duration::from_millis),
+                read_options,
+            )
+            .await?
+            .map(ChannelEvent::try_from);
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn ack(&self, request: Request<AckRequest>) -> Result<Response<AckReply>, Status> {
+        let req = request.into_inner();
+        let event_ids = req.event_ids.into_iter().collect();
+        let acked = self
+            .megaphone_svc
+            .ack(&req.consumer_address, req.consumer, &event_ids)?;
+        Ok(Response::new(AckReply {
+            acked: acked as u64,
+        }))
+    }
+
+    async fn close(&self, request: Request<CloseRequest>) -> Result<Response<CloseReply>, Status> {
+        let req = request.into_inner();
+        self.megaphone_svc
+            .close_channel(&req.producer_address)
+            .await?;
+        Ok(Response::new(CloseReply {}))
+    }
+}
+
+impl CreateChannelRequest {
+    fn channel_options(&self, defaults: ChannelOptions) -> ChannelOptions {
+        ChannelOptions {
+            buffer_size: self
+                .buffer_size
+                .map(|size| size as usize)
+                .unwrap_or(defaults.buffer_size),
+            overflow_policy: self
+                .overflow_policy
+                .map(|_| self.overflow_policy().into())
+                .unwrap_or(defaults.overflow_policy),
+            write_timeout: self
+                .write_timeout_millis
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.write_timeout),
+            channel_ttl: self
+                .channel_ttl_millis
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.channel_ttl),
+            event_ttl: self
+                .event_ttl_millis
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.event_ttl),
+            replay_size: self
+                .replay_size
+                .map(|size| size as usize)
+                .unwrap_or(defaults.replay_size),
+            consumer_mode: self
+                .consumer_mode
+                .map(|_| self.consumer_mode().into())
+                .unwrap_or(defaults.consumer_mode),
+            message_endpoint: self.message_endpoint.clone().or(defaults.message_endpoint),
+            streams: if self.streams.is_empty() {
+                defaults.streams
+            } else {
+                self.streams.clone()
+            },
+            ack_mode: self.ack_mode.unwrap_or(defaults.ack_mode),
+            visibility_timeout: self
+                .visibility_timeout_millis
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.visibility_timeout),
+            max_bytes: self
+                .max_bytes
+                .map(|max_bytes| max_bytes as usize)
+                .unwrap_or(defaults.max_bytes),
+        }
+    }
+}
+
+impl TryFrom<EventDto> for ChannelEvent {
+    type Error = Status;
+
+    fn try_from(value: EventDto) -> Result<Self, Self::Error> {
+        Ok(Self {
+            json_payload: serde_json::to_string(&value.body).map_err(|err| {
+                Status::internal(format!("Cannot serialize json payload - {err}"))
+            })?,
+            stream_id: value.stream_id,
+            event_id: value.event_id,
+            timestamp: Some(datetime_to_timestamp(value.timestamp)),
+            content_type: value
+                .binary
+                .as_ref()
+                .map(|binary| binary.content_type.clone()),
+            binary_payload: value
+                .binary
+                .map(|binary| binary.data.to_vec())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+fn channel_message(
+    event: EventDto,
+    ttl_millis: Option<u64>,
+    expires_at: Option<Timestamp>,
+) -> ChannelMessage<EventDto> {
+    ChannelMessage::new(
+        event,
+        ttl_millis.map(Duration::from_millis),
+        expires_at
+            .and_then(timestamp_to_datetime)
+            .map(SystemTime::from),
+    )
+}
+
+/// Binary event when the content type is set, json event otherwise
+fn parse_event(
+    stream_id: String,
+    json_payload: &str,
+    content_type: Option<String>,
+    binary_payload: Vec<u8>,
+) -> Result<EventDto, MegaphoneError> {
+    if let Some(content_type) = content_type {
+        return Ok(EventDto::binary(
+            stream_id,
+            content_type,
+            Bytes::from(binary_payload),
+        ));
+    }
+    let body = serde_json::from_str(json_payload).map_err(|err| {
+        MegaphoneError::BadRequest(format!("Cannot deserialize json payload - {err}"))
+    })?;
+    Ok(EventDto::new(stream_id, body))
+}
@@ -1,2 +1,4 @@
+pub mod channel_service;
+pub mod pipe;
 pub mod server;
 pub mod sync_service;
@@ -0,0 +1,59 @@
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tonic::codec::CompressionEncoding;
+use tonic::codegen::tokio_stream::wrappers;
+
+use crate::core::error::MegaphoneError;
+use crate::dto::message::EventDto;
+use crate::grpc::server::megaphone::sync_service_client::SyncServiceClient;
+use crate::grpc::server::megaphone::SyncRequest;
+use crate::service::agents_manager_service::{AgentsManagerService, SyncEvent};
+use crate::service::megaphone_service::MegaphoneService;
+
+/// Pipe the agent to the target megaphone, the agent channels are created on the target and the
+/// following events are forwarded to it.
+///
+/// Returns the pipe sender along with the task forwarding the events, the task completes once
+/// every sender is dropped and the queued events are delivered. With `compression` the events
+/// are sent gzip compressed.
+pub async fn pipe_agent(
+    agent_mgr: &AgentsManagerService,
+    channels_mgr: &MegaphoneService<EventDto>,
+    name: &str,
+    target: String,
+    compression: bool,
+) -> Result<(mpsc::Sender<SyncEvent>, JoinHandle<()>), MegaphoneError> {
+    let mut client = SyncServiceClient::connect(target).await.map_err(|err| {
+        MegaphoneError::InternalError(format!("Error during connection establishment - {err}"))
+    })?;
+    if compression {
+        client = client.send_compressed(CompressionEncoding::Gzip);
+    }
+    let (tx, rx) = mpsc::channel(500);
+    let forwarder = tokio::spawn(async move {
+        match client
+            .forward_events(wrappers::ReceiverStream::new(rx).map(SyncRequest::from))
+            .await
+        {
+            Ok(ok) => log::info!("Pipe terminated with message - {}", ok.into_inner().message),
+            Err(err) => log::error!("Pipe terminated with error - {err}"),
+        }
+    });
+    agent_mgr.register_pipe(name, tx.clone())?;
+    let channels = channels_mgr.channels_by_agent(name).collect::<Vec<_>>();
+    for (channel_id, options, topics, labels) in channels {
+        let out = tx
+            .send(SyncEvent::ChannelCreated {
+                id: channel_id,
+                options,
+                topics,
+                labels,
+            })
+            .await;
+        if let Err(err) = out {
+            log::error!("Error registering channel - {err}")
+        }
+    }
+    Ok((tx, forwarder))
+}
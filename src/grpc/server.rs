@@ -1,8 +1,12 @@
+use std::time::Duration;
+
 use chrono::{DateTime, Utc};
 use prost_types::Timestamp;
 
+use crate::core::config::{ConsumerMode, OverflowPolicy};
 use crate::grpc::server::megaphone::SyncRequest;
 use crate::service::agents_manager_service::SyncEvent;
+use crate::service::megaphone_service::ChannelOptions;
 
 pub mod megaphone {
     tonic::include_proto!("megaphone"); // The string specified here must match the proto package name
@@ -28,27 +32,126 @@ impl From<SyncEvent> for megaphone::sync_request::SyncEvent {
             SyncEvent::PipeAgentEnd { name } => {
                 Self::PipeAgentEnd(megaphone::PipeAgentEnd { agent_id: name })
             }
-            SyncEvent::ChannelCreated { id } => {
-                Self::ChannelCreated(megaphone::ChannelCreated { channel_id: id })
-            }
+            SyncEvent::ChannelCreated {
+                id,
+                options,
+                topics,
+                labels,
+            } => Self::ChannelCreated(megaphone::ChannelCreated {
+                channel_id: id,
+                options: Some(From::from(options)),
+                topics,
+                labels: labels.into_iter().collect(),
+            }),
             SyncEvent::ChannelDisposed { id } => {
                 Self::ChannelDisposed(megaphone::ChannelDisposed { channel_id: id })
             }
-            SyncEvent::EventReceived { channel, event } => {
-                Self::EventReceived(megaphone::EventReceived {
-                    channel_id: channel,
-                    stream_id: event.stream_id,
-                    event_id: event.event_id,
-                    timestamp: Some(datetime_to_timestamp(event.timestamp)),
-                    json_payload: serde_json::to_string(&event.body)
-                        .expect("Error serializing payload"),
-                })
-            }
+            SyncEvent::EventReceived {
+                channel,
+                event,
+                expires_at,
+                idempotency_key,
+            } => Self::EventReceived(megaphone::EventReceived {
+                channel_id: channel,
+                stream_id: event.stream_id,
+                event_id: event.event_id,
+                timestamp: Some(datetime_to_timestamp(event.timestamp)),
+                json_payload: serde_json::to_string(&event.body)
+                    .expect("Error serializing payload"),
+                expires_at: expires_at.map(|ts| datetime_to_timestamp(ts.into())),
+                idempotency_key,
+                content_type: event
+                    .binary
+                    .as_ref()
+                    .map(|binary| binary.content_type.clone()),
+                binary_payload: event
+                    .binary
+                    .map(|binary| binary.data.to_vec())
+                    .unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+impl From<OverflowPolicy> for megaphone::OverflowPolicy {
+    fn from(value: OverflowPolicy) -> Self {
+        match value {
+            OverflowPolicy::Block => Self::Block,
+            OverflowPolicy::Reject => Self::Reject,
+            OverflowPolicy::DropOldest => Self::DropOldest,
+            OverflowPolicy::DropNewest => Self::DropNewest,
+        }
+    }
+}
+
+impl From<megaphone::OverflowPolicy> for OverflowPolicy {
+    fn from(value: megaphone::OverflowPolicy) -> Self {
+        match value {
+            megaphone::OverflowPolicy::Block => Self::Block,
+            megaphone::OverflowPolicy::Reject => Self::Reject,
+            megaphone::OverflowPolicy::DropOldest => Self::DropOldest,
+            megaphone::OverflowPolicy::DropNewest => Self::DropNewest,
+        }
+    }
+}
+
+impl From<ConsumerMode> for megaphone::ConsumerMode {
+    fn from(value: ConsumerMode) -> Self {
+        match value {
+            ConsumerMode::Exclusive => Self::Exclusive,
+            ConsumerMode::FanOut => Self::FanOut,
+        }
+    }
+}
+
+impl From<megaphone::ConsumerMode> for ConsumerMode {
+    fn from(value: megaphone::ConsumerMode) -> Self {
+        match value {
+            megaphone::ConsumerMode::Exclusive => Self::Exclusive,
+            megaphone::ConsumerMode::FanOut => Self::FanOut,
+        }
+    }
+}
+
+impl From<ChannelOptions> for megaphone::ChannelOptions {
+    fn from(value: ChannelOptions) -> Self {
+        Self {
+            buffer_size: value.buffer_size as u64,
+            overflow_policy: megaphone::OverflowPolicy::from(value.overflow_policy).into(),
+            write_timeout_millis: value.write_timeout.as_millis() as u64,
+            channel_ttl_millis: value.channel_ttl.as_millis() as u64,
+            event_ttl_millis: value.event_ttl.as_millis() as u64,
+            replay_size: value.replay_size as u64,
+            consumer_mode: megaphone::ConsumerMode::from(value.consumer_mode).into(),
+            message_endpoint: value.message_endpoint.unwrap_or_default(),
+            streams: value.streams,
+            ack_mode: value.ack_mode,
+            visibility_timeout_millis: value.visibility_timeout.as_millis() as u64,
+            max_bytes: value.max_bytes as u64,
+        }
+    }
+}
+
+impl From<megaphone::ChannelOptions> for ChannelOptions {
+    fn from(value: megaphone::ChannelOptions) -> Self {
+        Self {
+            buffer_size: value.buffer_size as usize,
+            overflow_policy: value.overflow_policy().into(),
+            write_timeout: Duration::from_millis(value.write_timeout_millis),
+            channel_ttl: Duration::from_millis(value.channel_ttl_millis),
+            event_ttl: Duration::from_millis(value.event_ttl_millis),
+            replay_size: value.replay_size as usize,
+            consumer_mode: value.consumer_mode().into(),
+            message_endpoint: Some(value.message_endpoint).filter(|endpoint| !endpoint.is_empty()),
+            streams: value.streams,
+            ack_mode: value.ack_mode,
+            visibility_timeout: Duration::from_millis(value.visibility_timeout_millis),
+            max_bytes: value.max_bytes as usize,
         }
     }
 }
 
-fn datetime_to_timestamp(datetime: DateTime<Utc>) -> Timestamp {
+pub fn datetime_to_timestamp(datetime: DateTime<Utc>) -> Timestamp {
     Timestamp {
         seconds: datetime.timestamp(),
         nanos: datetime
@@ -1,19 +1,27 @@
 use std::cmp;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
+use bytes::Bytes;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use futures::StreamExt;
+use metrics::counter;
 use prost_types::Timestamp;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 use tonic::{Request, Response, Status, Streaming};
 
-use megaphone::dto::message::EventDto;
-
 use crate::core::error::MegaphoneError;
+use crate::dto::message::{BinaryPayloadDto, EventDto};
 use crate::grpc::server::megaphone::sync_request::SyncEvent;
 use crate::grpc::server::megaphone::sync_service_server::SyncService;
 use crate::grpc::server::megaphone::{EventReceived, SyncReply, SyncRequest};
 use crate::service::agents_manager_service::AgentsManagerService;
-use crate::service::megaphone_service::MegaphoneService;
+use crate::service::channel_log::ChannelMessage;
+use crate::service::megaphone_service::{MegaphoneService, MESSAGES_LOST_METRIC_NAME};
+
+/// Piped events of a channel waiting to be injected
+const INJECT_QUEUE_SIZE: usize = 500;
 
 pub struct MegaphoneSyncService {
     agent_mgr: AgentsManagerService,
@@ -37,7 +45,18 @@ impl SyncService for MegaphoneSyncService {
     ) -> Result<Response<SyncReply>, Status> {
         let mut stream = request.into_inner();
         let mut piped_agents = HashSet::new();
-        while let Some(stream_item) = stream.next().await {
+        let mut injectors = ChannelInjectors::new(self.megaphone_svc.clone());
+        loop {
+            let stream_item = tokio::select! {
+                stream_item = stream.next() => match stream_item {
+                    Some(stream_item) => stream_item,
+                    None => break,
+                },
+                () = self.megaphone_svc.shutting_down() => {
+                    log::info!("Closing inbound pipe, server is shutting down");
+                    break;
+                }
+            };
             match stream_item {
                 Ok(SyncRequest {
                     sync_event: Some(SyncEvent::PipeAgentStart(req)),
@@ -81,7 +100,12 @@ impl SyncService for MegaphoneSyncService {
                 }) => {
                     let out = self
                         .megaphone_svc
-                        .create_channel_with_id(&req.channel_id)
+                        .create_channel_with_id(
+                            &req.channel_id,
+                            req.options.map(From::from),
+                            &req.topics,
+                            req.labels.into_iter().collect(),
+                        )
                         .await;
                     if let Err(err) = out {
                         log::error!("Error processing channel-created - {err}");
@@ -91,10 +115,22 @@ impl SyncService for MegaphoneSyncService {
                     sync_event: Some(SyncEvent::EventReceived(req)),
                 }) => {
                     let channel_id = req.channel_id.clone();
-                    let out = EventDto::try_from(req)
-                        .and_then(|evt| self.megaphone_svc.inject_into_channel(&channel_id, evt));
-                    if let Err(err) = out {
-                        log::error!("Error processing event-received - {err}");
+                    let expires_at = req
+                        .expires_at
+                        .clone()
+                        .and_then(timestamp_to_datetime)
+                        .map(SystemTime::from);
+                    let idempotency_key = req.idempotency_key.clone();
+                    match EventDto::try_from(req) {
+                        Ok(evt) => injectors.inject(
+                            channel_id,
+                            ChannelMessage {
+                                expires_at,
+                                idempotency_key,
+                                ..ChannelMessage::from(evt)
+                            },
+                        ),
+                        Err(err) => log::error!("Error processing event-received - {err}"),
                     }
                 }
                 Ok(SyncRequest { sync_event: None }) => {
@@ -116,7 +152,54 @@ impl SyncService for MegaphoneSyncService {
     }
 }
 
-fn timestamp_to_datetime(timestamp: Timestamp) -> Option<DateTime<Utc>> {
+/// Injects the piped events of every channel from a dedicated task, so that a full blocking
+/// channel holds its own events without stalling the other channels of the pipe
+struct ChannelInjectors {
+    megaphone_svc: MegaphoneService<EventDto>,
+    injectors: HashMap<String, mpsc::Sender<ChannelMessage<EventDto>>>,
+}
+
+impl ChannelInjectors {
+    fn new(megaphone_svc: MegaphoneService<EventDto>) -> Self {
+        Self {
+            megaphone_svc,
+            injectors: HashMap::new(),
+        }
+    }
+
+    /// Queue the event behind the previous events of the channel, the event is discarded if the
+    /// channel queue is full
+    fn inject(&mut self, channel_id: String, message: ChannelMessage<EventDto>) {
+        let injector = self.injectors.entry(channel_id.clone()).or_insert_with(|| {
+            let (tx, mut rx) = mpsc::channel(INJECT_QUEUE_SIZE);
+            let megaphone_svc = self.megaphone_svc.clone();
+            let channel_id = channel_id.clone();
+            tokio::spawn(async move {
+                while let Some(message) = rx.recv().await {
+                    let out = megaphone_svc
+                        .inject_into_channel(&channel_id, message)
+                        .await;
+                    if let Err(err) = out {
+                        log::error!("Error processing event-received - {err}");
+                    }
+                }
+            });
+            tx
+        });
+        match injector.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                counter!(MESSAGES_LOST_METRIC_NAME, "reason" => "overflow").increment(1);
+                log::error!("Discarding event piped to '{channel_id}' - channel is not draining");
+            }
+            Err(TrySendError::Closed(_)) => {
+                log::error!("Discarding event piped to '{channel_id}' - injector stopped");
+            }
+        }
+    }
+}
+
+pub fn timestamp_to_datetime(timestamp: Timestamp) -> Option<DateTime<Utc>> {
     let naive =
         NaiveDateTime::from_timestamp_opt(timestamp.seconds, cmp::max(0, timestamp.nanos) as u32)?;
     Some(DateTime::from_naive_utc_and_offset(naive, Utc))
@@ -126,6 +209,21 @@ impl TryFrom<EventReceived> for EventDto {
     type Error = MegaphoneError;
 
     fn try_from(value: EventReceived) -> Result<Self, Self::Error> {
+        let (body, binary) = match value.content_type {
+            Some(content_type) => (
+                serde_json::Value::Null,
+                Some(BinaryPayloadDto {
+                    content_type,
+                    data: Bytes::from(value.binary_payload),
+                }),
+            ),
+            None => (
+                serde_json::from_str(&value.json_payload).map_err(|err| {
+                    MegaphoneError::BadRequest(format!("Cannot deserialize json payload - {err}"))
+                })?,
+                None,
+            ),
+        };
         Ok(Self {
             stream_id: value.stream_id,
             event_id: value.event_id,
@@ -133,9 +231,8 @@ impl TryFrom<EventReceived> for EventDto {
                 .timestamp
                 .and_then(timestamp_to_datetime)
                 .unwrap_or_else(Utc::now),
-            body: serde_json::from_str(&value.json_payload).map_err(|err| {
-                MegaphoneError::BadRequest(format!("Cannot deserialize json payload - {err}"))
-            })?,
+            body,
+            binary,
         })
     }
 }
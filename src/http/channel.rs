@@ -1,62 +1,167 @@
+use std::convert::Infallible;
+use std::future::ready;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use axum::body::StreamBody;
 use axum::extract::{Path, Query, State};
 use axum::http::{header, HeaderMap, StatusCode};
-use axum::response::IntoResponse;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::{BoxError, Json};
-use futures::StreamExt;
+use bytes::Bytes;
+use futures::{FutureExt, Stream, StreamExt};
 use tokio::sync::RwLock;
 
 use megaphone::dto::agent::{BasicOutcomeDto, OutcomeStatus};
-use megaphone::dto::channel::{
-    ChanExistsReqDto, ChanExistsResDto, ChannelCreateReqDto, ChannelCreateResDto, ChannelInfoDto,
-    ChannelsListParams, WriteBatchReqDto, WriteBatchResDto,
-};
+use megaphone::dto::channel::{ChanExistsReqDto, ChanExistsResDto, WriteBatchResDto};
 use megaphone::dto::error::ErrorDto;
-use megaphone::dto::message::EventDto;
 
 use crate::core::config::MegaphoneConfig;
 use crate::core::error::MegaphoneError;
-use crate::service::megaphone_service::MegaphoneService;
+use crate::core::label_selector::LabelSelector;
+use crate::dto::channel::{
+    AckReqDto, AckResDto, ChannelCreateReqDto, ChannelCreateResDto, ChannelInfoDto,
+    ChannelsListParams, MessageExpirationDto, ReadChannelParams, ReadModeDto, WriteBatchReqDto,
+};
+use crate::dto::message::EventDto;
+use crate::http::encoding::compress_negotiated;
+use crate::service::channel_log::ChannelMessage;
+use crate::service::megaphone_service::{MegaphoneService, ReadOptions};
+
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const EVENT_STREAM_MIME: &str = "text/event-stream";
+const JSON_MIME: &str = "application/json";
+const DEFAULT_BINARY_MIME: &str = "application/octet-stream";
 
 pub async fn create_handler(
     State(svc): State<MegaphoneService<EventDto>>,
     body_opt: Option<Json<ChannelCreateReqDto>>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<ErrorDto>)> {
     let Json(req) = body_opt.unwrap_or_default();
-    let (agent_name, channel_id, producer_address, protocols) =
-        svc.create_channel(&req.protocols).await?;
+    let options = req.channel_options(svc.default_options());
+    let (agent_name, channel_id, producer_address, protocols) = svc
+        .create_channel(
+            &req.base.protocols,
+            options.clone(),
+            &req.topics,
+            req.labels.clone(),
+        )
+        .await?;
     Ok(Json(ChannelCreateResDto {
-        producer_address,
-        consumer_address: String::from(&channel_id),
-        channel_id,
-        agent_name,
-        protocols,
+        base: megaphone::dto::channel::ChannelCreateResDto {
+            producer_address,
+            consumer_address: String::from(&channel_id),
+            channel_id,
+            agent_name,
+            protocols,
+        },
+        buffer_size: options.buffer_size,
+        overflow_policy: options.overflow_policy.into(),
+        write_timeout_secs: options.write_timeout.as_secs(),
+        channel_ttl_secs: options.channel_ttl.as_secs(),
+        event_ttl_secs: options.event_ttl.as_secs(),
+        replay_size: options.replay_size,
+        consumer_mode: options.consumer_mode.into(),
+        message_endpoint: options.message_endpoint,
+        topics: req.topics,
+        streams: options.streams,
+        ack_mode: options.ack_mode,
+        visibility_timeout_secs: options.visibility_timeout.as_secs(),
+        max_bytes: options.max_bytes,
+        labels: req.labels,
     }))
 }
 
 pub async fn read_handler(
     Path(channel_id): Path<String>,
+    Query(params): Query<ReadChannelParams>,
+    headers: HeaderMap,
     State(conf): State<Arc<RwLock<MegaphoneConfig>>>,
     State(svc): State<MegaphoneService<EventDto>>,
-) -> Result<impl IntoResponse, (StatusCode, Json<ErrorDto>)> {
-    let duration = {
+) -> Result<Response, (StatusCode, Json<ErrorDto>)> {
+    let (duration, sse_keep_alive, sse_retry) = {
         let conf_read = conf.read().await;
-        conf_read.poll_duration_millis
+        (
+            conf_read.poll_duration_millis,
+            Duration::from_secs(conf_read.sse_keep_alive_secs),
+            Duration::from_millis(conf_read.sse_retry_millis),
+        )
+    };
+    let streams = params.stream_patterns();
+    let after = params.after.or_else(|| {
+        headers
+            .get(LAST_EVENT_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    });
+    let read_options = ReadOptions {
+        after,
+        streams,
+        consumer: params.consumer,
     };
     let stream = svc
-        .read_channel(channel_id, Duration::from_millis(duration))
-        .await?
-        .map(|evt| {
-            serde_json::to_string(&evt)
-                .map(|mut s| {
-                    s.push('\n');
-                    s
-                })
-                .map_err(BoxError::from)
-        });
+        .read_channel(
+            channel_id,
+            Some(Duration::from_millis(duration)),
+            read_options,
+        )
+        .await?;
+
+    let response = read_response(
+        stream,
+        params.mode,
+        params.max,
+        &headers,
+        sse_keep_alive,
+        sse_retry,
+    )
+    .await;
+    Ok(compress_negotiated(response, &headers))
+}
+
+async fn read_response(
+    stream: impl Stream<Item = EventDto> + Send + 'static,
+    mode: ReadModeDto,
+    max: Option<usize>,
+    headers: &HeaderMap,
+    sse_keep_alive: Duration,
+    sse_retry: Duration,
+) -> Response {
+    if mode == ReadModeDto::Poll {
+        let max = max.unwrap_or(usize::MAX);
+        futures::pin_mut!(stream);
+        let mut events = Vec::new();
+        if max > 0 {
+            events.extend(stream.next().await);
+        }
+        while !events.is_empty() && events.len() < max {
+            match stream.next().now_or_never() {
+                Some(Some(evt)) => events.push(evt),
+                _ => break,
+            }
+        }
+        return Json(events).into_response();
+    }
+
+    if accepts_event_stream(headers) {
+        let events = futures::stream::once(ready(Event::default().retry(sse_retry)))
+            .chain(stream.map(sse_event))
+            .map(Ok::<_, Infallible>);
+        let sse = Sse::new(events).keep_alive(KeepAlive::new().interval(sse_keep_alive));
+        return sse.into_response();
+    }
+
+    let stream = stream.map(|evt| {
+        serde_json::to_string(&evt)
+            .map(|mut s| {
+                s.push('\n');
+                s
+            })
+            .map_err(BoxError::from)
+    });
     let body = StreamBody::new(stream);
 
     let mut headers = HeaderMap::new();
@@ -65,16 +170,75 @@ pub async fn read_handler(
         "application/x-ndjson".parse().unwrap(),
     );
 
-    Ok((headers, body))
+    (headers, body).into_response()
+}
+
+fn accepts_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains(EVENT_STREAM_MIME))
+}
+
+fn sse_event(evt: EventDto) -> Event {
+    let event = Event::default()
+        .id(sse_field(&evt.event_id))
+        .event(sse_field(&evt.stream_id));
+    // SSE is text only, binary payloads are sent base64 encoded along with the content type
+    match &evt.binary {
+        Some(binary) => event.json_data(binary),
+        None => event.json_data(&evt.body),
+    }
+    .unwrap_or_else(|err| {
+        log::error!("Error serializing event {} - {err}", evt.event_id);
+        Event::default().comment("serialization error")
+    })
+}
+
+/// Newlines and null characters are not allowed in SSE fields
+fn sse_field(value: &str) -> String {
+    value.replace(['\r', '\n', '\0'], "")
+}
+
+pub async fn ack_handler(
+    Path(channel_id): Path<String>,
+    State(svc): State<MegaphoneService<EventDto>>,
+    Json(req): Json<AckReqDto>,
+) -> Result<Json<AckResDto>, (StatusCode, Json<ErrorDto>)> {
+    let acked = svc.ack(&channel_id, req.consumer, &req.event_ids)?;
+    Ok(Json(AckResDto { acked }))
+}
+
+pub async fn close_handler(
+    Path(channel_id): Path<String>,
+    State(svc): State<MegaphoneService<EventDto>>,
+) -> Result<(StatusCode, Json<BasicOutcomeDto>), (StatusCode, Json<ErrorDto>)> {
+    svc.close_channel(&channel_id).await?;
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(BasicOutcomeDto {
+            status: OutcomeStatus::Ok,
+        }),
+    ))
 }
 
 pub async fn write_handler(
     Path((channel_id, stream_id)): Path<(String, String)>,
+    Query(expiration): Query<MessageExpirationDto>,
+    headers: HeaderMap,
     State(svc): State<MegaphoneService<EventDto>>,
-    Json(body): Json<serde_json::Value>,
+    body: Bytes,
 ) -> Result<(StatusCode, Json<BasicOutcomeDto>), (StatusCode, Json<ErrorDto>)> {
-    svc.write_into_channel(&channel_id, EventDto::new(stream_id, body))
-        .await?;
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let event = written_event(stream_id, &headers, body)?;
+    let message = expiration
+        .channel_message(event)
+        .with_idempotency_key(idempotency_key);
+    svc.write_into_channel(&channel_id, message).await?;
     Ok((
         StatusCode::CREATED,
         Json(BasicOutcomeDto {
@@ -83,33 +247,67 @@ pub async fn write_handler(
     ))
 }
 
+/// Json bodies are parsed, any other content type is stored as raw bytes
+fn written_event(
+    stream_id: String,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<EventDto, MegaphoneError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or(DEFAULT_BINARY_MIME);
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if essence != JSON_MIME && !essence.ends_with("+json") {
+        return Ok(EventDto::binary(
+            stream_id,
+            String::from(content_type),
+            body,
+        ));
+    }
+    let body = serde_json::from_slice(&body).map_err(|err| {
+        MegaphoneError::BadRequest(format!("Cannot deserialize json payload - {err}"))
+    })?;
+    Ok(EventDto::new(stream_id, body))
+}
+
 pub async fn write_batch_handler(
     State(svc): State<MegaphoneService<EventDto>>,
+    headers: HeaderMap,
     Json(body): Json<WriteBatchReqDto>,
-) -> Result<(StatusCode, Json<WriteBatchResDto>), (StatusCode, Json<ErrorDto>)> {
+) -> Result<Response, (StatusCode, Json<ErrorDto>)> {
     let messages = body
         .messages
         .into_iter()
-        .map(|message| EventDto::new(message.stream_id, message.body))
+        .map(ChannelMessage::from)
         .collect();
 
     let failures = svc
         .write_batch_into_channels(&body.channels.into_iter().collect::<Vec<_>>()[..], messages)
         .await;
-    Ok((StatusCode::CREATED, Json(WriteBatchResDto { failures })))
+    let response = (StatusCode::CREATED, Json(WriteBatchResDto { failures })).into_response();
+    Ok(compress_negotiated(response, &headers))
 }
 
 pub async fn channel_exists_handler(
     State(svc): State<MegaphoneService<EventDto>>,
+    headers: HeaderMap,
     Json(req): Json<ChanExistsReqDto>,
-) -> Result<impl IntoResponse, (StatusCode, Json<ErrorDto>)> {
-    Ok(Json(ChanExistsResDto {
+) -> Result<Response, (StatusCode, Json<ErrorDto>)> {
+    let response = Json(ChanExistsResDto {
         channels: req
             .channels
             .into_iter()
             .map(|id| (id.clone(), svc.channel_exists(&id)))
             .collect(),
-    }))
+    })
+    .into_response();
+    Ok(compress_negotiated(response, &headers))
 }
 
 pub async fn channel_delete_handler(
@@ -126,8 +324,22 @@ pub async fn channels_list_handler(
     Query(params): Query<ChannelsListParams>,
     State(svc): State<MegaphoneService<EventDto>>,
 ) -> Result<Json<Vec<ChannelInfoDto>>, (StatusCode, Json<ErrorDto>)> {
+    let selector = params
+        .selector
+        .as_deref()
+        .map(LabelSelector::from_str)
+        .transpose()?
+        .unwrap_or_default();
     let channels = svc
-        .list_channels(params.skip, params.limit)
-        .map_err(|e| MegaphoneError::InternalError(format!("Error retrieving channels - {e}")))?;
+        .list_channels(params.skip, params.limit, &selector)
+        .map_err(|e| MegaphoneError::InternalError(format!("Error retrieving channels - {e}")))?
+        .into_iter()
+        .map(|(base, labels, stats)| ChannelInfoDto {
+            base,
+            redeliveries: stats.redeliveries,
+            bytes: stats.bytes,
+            labels,
+        })
+        .collect();
     Ok(Json(channels))
 }
@@ -0,0 +1,217 @@
+use std::io;
+use std::mem;
+
+use async_compression::tokio::write::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use axum::body::{HttpBody, StreamBody};
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use axum::BoxError;
+use bytes::Bytes;
+use futures::Stream;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Compression applied to HTTP responses, negotiated with the `Accept-Encoding` header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// Encoding with the highest quality value accepted by the client, ties are broken by the
+    /// server preference: zstd, br, gzip
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get_all(header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|item| {
+                let mut params = item.split(';');
+                let encoding = match params.next()?.trim().to_ascii_lowercase().as_str() {
+                    "gzip" => Self::Gzip,
+                    "br" => Self::Brotli,
+                    "zstd" => Self::Zstd,
+                    _ => return None,
+                };
+                let quality = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|quality| quality.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((encoding, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .max_by(|(a, a_quality), (b, b_quality)| {
+                a_quality
+                    .total_cmp(b_quality)
+                    .then(a.preference().cmp(&b.preference()))
+            })
+            .map(|(encoding, _)| encoding)
+    }
+
+    fn preference(&self) -> u8 {
+        match self {
+            Self::Gzip => 0,
+            Self::Brotli => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        })
+    }
+}
+
+enum Encoder {
+    Gzip(GzipEncoder<Vec<u8>>),
+    Brotli(Box<BrotliEncoder<Vec<u8>>>),
+    Zstd(ZstdEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: ContentEncoding) -> Self {
+        match encoding {
+            ContentEncoding::Gzip => Self::Gzip(GzipEncoder::new(Vec::new())),
+            ContentEncoding::Brotli => Self::Brotli(Box::new(BrotliEncoder::new(Vec::new()))),
+            ContentEncoding::Zstd => Self::Zstd(ZstdEncoder::new(Vec::new())),
+        }
+    }
+
+    fn writer(&mut self) -> &mut (dyn AsyncWrite + Unpin + Send) {
+        match self {
+            Self::Gzip(encoder) => encoder,
+            Self::Brotli(encoder) => encoder,
+            Self::Zstd(encoder) => encoder,
+        }
+    }
+
+    fn take_output(&mut self) -> Bytes {
+        let output = match self {
+            Self::Gzip(encoder) => encoder.get_mut(),
+            Self::Brotli(encoder) => encoder.get_mut(),
+            Self::Zstd(encoder) => encoder.get_mut(),
+        };
+        Bytes::from(mem::take(output))
+    }
+
+    /// Compress the chunk and flush it, so the client can decode it without waiting for more data
+    async fn compress(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        self.writer().write_all(chunk).await?;
+        self.writer().flush().await?;
+        Ok(self.take_output())
+    }
+
+    async fn finish(&mut self) -> io::Result<Bytes> {
+        self.writer().shutdown().await?;
+        Ok(self.take_output())
+    }
+}
+
+/// Compress the response body, every chunk of the original body is flushed as soon as it is
+/// produced so that streamed events are not delayed
+pub fn compress_response(response: Response, encoding: ContentEncoding) -> Response {
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_ENCODING, encoding.header_value());
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    let body = StreamBody::new(compressed_stream(body, Encoder::new(encoding)));
+    (parts, body).into_response()
+}
+
+/// Compress the response with the encoding negotiated from the request headers, if any
+pub fn compress_negotiated(response: Response, headers: &HeaderMap) -> Response {
+    match ContentEncoding::negotiate(headers) {
+        Some(encoding) => compress_response(response, encoding),
+        None => response,
+    }
+}
+
+fn compressed_stream<B>(body: B, encoder: Encoder) -> impl Stream<Item = Result<Bytes, BoxError>>
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    futures::stream::unfold(
+        (body, Some(encoder)),
+        |(mut body, mut encoder)| async move {
+            encoder.as_ref()?;
+            let output = match body.data().await {
+                Some(Ok(chunk)) => encoder.as_mut()?.compress(&chunk).await,
+                Some(Err(err)) => {
+                    encoder = None;
+                    return Some((Err(err.into()), (body, encoder)));
+                }
+                // The stream ends right after the trailing bytes of the encoder
+                None => encoder.take()?.finish().await,
+            };
+            let output = output.map_err(BoxError::from);
+            Some((output, (body, encoder)))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::bufread::GzipDecoder;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn highest_quality_encoding_is_negotiated() {
+        assert_eq!(
+            ContentEncoding::negotiate(&accept("gzip;q=0.9, br;q=0.5")),
+            Some(ContentEncoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn ties_prefer_the_server_order() {
+        assert_eq!(
+            ContentEncoding::negotiate(&accept("gzip, br, zstd")),
+            Some(ContentEncoding::Zstd)
+        );
+    }
+
+    #[test]
+    fn unknown_and_refused_encodings_are_ignored() {
+        assert_eq!(
+            ContentEncoding::negotiate(&accept("deflate, gzip;q=0")),
+            None
+        );
+        assert_eq!(ContentEncoding::negotiate(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn negotiated_response_is_compressed() {
+        let response = compress_negotiated("hello".into_response(), &accept("gzip"));
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let mut body = response.into_body();
+        let mut compressed = Vec::new();
+        while let Some(chunk) = body.data().await {
+            compressed.extend_from_slice(&chunk.unwrap());
+        }
+        let mut decoded = String::new();
+        GzipDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded, "hello");
+    }
+}
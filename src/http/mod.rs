@@ -1,2 +1,6 @@
 pub mod channel;
+pub mod encoding;
+pub mod socket;
+pub mod topic;
 pub mod vagent;
+pub mod webhook;
@@ -0,0 +1,137 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::Response;
+use axum::Json;
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+use megaphone::dto::error::ErrorDto;
+
+use crate::core::config::MegaphoneConfig;
+use crate::dto::channel::{ConsumerMessageDto, ReadChannelParams};
+use crate::dto::message::EventDto;
+use crate::service::megaphone_service::{MegaphoneService, ReadOptions};
+
+pub async fn ws_handler(
+    Path(channel_id): Path<String>,
+    Query(params): Query<ReadChannelParams>,
+    State(conf): State<Arc<RwLock<MegaphoneConfig>>>,
+    State(svc): State<MegaphoneService<EventDto>>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, Json<ErrorDto>)> {
+    let ping_interval = {
+        let conf_read = conf.read().await;
+        Duration::from_secs(conf_read.ws_ping_interval_secs)
+    };
+    let read_options = ReadOptions {
+        streams: params.stream_patterns(),
+        after: params.after,
+        consumer: params.consumer,
+    };
+    let stream = svc
+        .read_channel(channel_id.clone(), None, read_options)
+        .await?;
+    Ok(ws.on_upgrade(move |socket| {
+        serve_socket(socket, stream, ping_interval, move |msg| {
+            forward_message(&svc, &channel_id, msg)
+        })
+    }))
+}
+
+fn forward_message(svc: &MegaphoneService<EventDto>, channel_id: &str, msg: &str) {
+    let msg = match serde_json::from_str::<ConsumerMessageDto>(msg) {
+        Ok(msg) => msg,
+        Err(err) => {
+            log::warn!("Malformed consumer message on channel {channel_id} - {err}");
+            return;
+        }
+    };
+    if let Err(err) = svc.forward_consumer_message(channel_id, &msg.stream_id, msg.body) {
+        log::warn!("Error forwarding consumer message on channel {channel_id} - {err}");
+    }
+}
+
+/// Json events are sent as text frames. Binary events are sent as binary frames holding the
+/// length of the json header as a big-endian u32, the header and the raw payload.
+fn socket_frame(evt: &EventDto) -> serde_json::Result<Message> {
+    let Some(binary) = &evt.binary else {
+        return serde_json::to_string(evt).map(Message::Text);
+    };
+    let header = serde_json::to_vec(&serde_json::json!({
+        "streamId": evt.stream_id,
+        "eventId": evt.event_id,
+        "timestamp": evt.timestamp,
+        "contentType": binary.content_type,
+    }))?;
+    let mut frame = Vec::with_capacity(4 + header.len() + binary.data.len());
+    frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(&binary.data);
+    Ok(Message::Binary(frame))
+}
+
+async fn serve_socket(
+    socket: WebSocket,
+    events: impl Stream<Item = EventDto>,
+    ping_interval: Duration,
+    on_message: impl Fn(&str),
+) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut ping = tokio::time::interval_at(Instant::now() + ping_interval, ping_interval);
+    let mut last_seen = Instant::now();
+    futures::pin_mut!(events);
+
+    loop {
+        tokio::select! {
+            evt = events.next() => {
+                let Some(evt) = evt else {
+                    break;
+                };
+                let frame = match socket_frame(&evt) {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        log::error!("Error serializing event {} - {err}", evt.event_id);
+                        continue;
+                    }
+                };
+                if let Err(err) = sender.send(frame).await {
+                    log::warn!("Error sending websocket frame - {err}");
+                    break;
+                }
+            }
+            _ = ping.tick() => {
+                if last_seen.elapsed() > ping_interval * 2 {
+                    log::debug!("Websocket peer is not responding, closing connection");
+                    break;
+                }
+                if let Err(err) = sender.send(Message::Ping(Vec::new())).await {
+                    log::warn!("Error sending websocket ping - {err}");
+                    break;
+                }
+            }
+            msg = receiver.next() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(Message::Text(msg))) => {
+                        last_seen = Instant::now();
+                        on_message(&msg);
+                    }
+                    Some(Ok(_)) => last_seen = Instant::now(),
+                    Some(Err(err)) => {
+                        log::warn!("Error receiving websocket message - {err}");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Err(err) = sender.close().await {
+        log::debug!("Error closing websocket - {err}");
+    }
+}
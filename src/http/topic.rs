@@ -0,0 +1,42 @@
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+
+use megaphone::dto::agent::{BasicOutcomeDto, OutcomeStatus};
+use megaphone::dto::channel::WriteBatchResDto;
+use megaphone::dto::error::ErrorDto;
+
+use crate::dto::channel::{MessageDto, TopicsReqDto};
+use crate::dto::message::EventDto;
+use crate::service::megaphone_service::MegaphoneService;
+
+pub async fn write_handler(
+    Path(topic): Path<String>,
+    State(svc): State<MegaphoneService<EventDto>>,
+    Json(message): Json<MessageDto>,
+) -> Result<(StatusCode, Json<WriteBatchResDto>), (StatusCode, Json<ErrorDto>)> {
+    let failures = svc.write_into_topic(&topic, message.into()).await;
+    Ok((StatusCode::CREATED, Json(WriteBatchResDto { failures })))
+}
+
+pub async fn subscribe_handler(
+    Path(channel_id): Path<String>,
+    State(svc): State<MegaphoneService<EventDto>>,
+    Json(req): Json<TopicsReqDto>,
+) -> Result<Json<BasicOutcomeDto>, (StatusCode, Json<ErrorDto>)> {
+    svc.subscribe(&channel_id, &req.topics)?;
+    Ok(Json(BasicOutcomeDto {
+        status: OutcomeStatus::Ok,
+    }))
+}
+
+pub async fn unsubscribe_handler(
+    Path(channel_id): Path<String>,
+    State(svc): State<MegaphoneService<EventDto>>,
+    Json(req): Json<TopicsReqDto>,
+) -> Result<Json<BasicOutcomeDto>, (StatusCode, Json<ErrorDto>)> {
+    svc.unsubscribe(&channel_id, &req.topics)?;
+    Ok(Json(BasicOutcomeDto {
+        status: OutcomeStatus::Ok,
+    }))
+}
@@ -1,22 +1,21 @@
+use std::sync::Arc;
+
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::Json;
-use futures::StreamExt;
-use tokio::sync::mpsc;
-use tonic::codegen::tokio_stream::wrappers;
+use tokio::sync::RwLock;
 
 use megaphone::dto::agent::{
     AddVirtualAgentReqDto, BasicOutcomeDto, PipeVirtualAgentReqDto, VirtualAgentItemDto,
     VirtualAgentModeDto,
 };
 use megaphone::dto::error::ErrorDto;
-use megaphone::dto::message::EventDto;
 
-use crate::core::error::MegaphoneError;
-use crate::grpc::server::megaphone::sync_service_client::SyncServiceClient;
-use crate::grpc::server::megaphone::SyncRequest;
-use crate::service::agents_manager_service::{AgentsManagerService, SyncEvent};
+use crate::core::config::MegaphoneConfig;
+use crate::dto::message::EventDto;
+use crate::grpc::pipe::pipe_agent;
+use crate::service::agents_manager_service::AgentsManagerService;
 use crate::service::megaphone_service::MegaphoneService;
 
 pub async fn list_virtual_agents(
@@ -48,29 +47,17 @@ pub async fn add_virtual_agent(
 pub async fn pipe_virtual_agent(
     State(agent_mgr): State<AgentsManagerService>,
     State(channels_mgr): State<MegaphoneService<EventDto>>,
+    State(conf): State<Arc<RwLock<MegaphoneConfig>>>,
     Json(req): Json<PipeVirtualAgentReqDto>,
 ) -> Result<(StatusCode, Json<BasicOutcomeDto>), (StatusCode, Json<ErrorDto>)> {
-    let mut client = SyncServiceClient::connect(req.target)
-        .await
-        .map_err(|err| {
-            MegaphoneError::InternalError(format!("Error during connection establishment - {err}"))
-        })?;
-    let (tx, rx) = mpsc::channel(500);
-    tokio::spawn(async move {
-        match client
-            .forward_events(wrappers::ReceiverStream::new(rx).map(SyncRequest::from))
-            .await
-        {
-            Ok(ok) => log::info!("Pipe terminated with message - {}", ok.into_inner().message),
-            Err(err) => log::error!("Pipe terminated with error - {err}"),
-        }
-    });
-    agent_mgr.register_pipe(&req.name, tx.clone())?;
-    for channel_id in channels_mgr.channel_ids_by_agent(&req.name) {
-        let out = tx.send(SyncEvent::ChannelCreated { id: channel_id }).await;
-        if let Err(err) = out {
-            log::error!("Error registering channel - {err}")
-        }
-    }
+    let compression = conf.read().await.sync_compression;
+    pipe_agent(
+        &agent_mgr,
+        &channels_mgr,
+        &req.name,
+        req.target,
+        compression,
+    )
+    .await?;
     Ok((StatusCode::ACCEPTED, Json(BasicOutcomeDto::ok())))
 }
@@ -0,0 +1,48 @@
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+
+use megaphone::dto::error::ErrorDto;
+
+use crate::core::error::MegaphoneError;
+use crate::dto::webhook::{OutboxEntryDto, OutboxPurgeParams, OutboxPurgeResDto};
+use crate::service::webhooks::WebhookDispatcher;
+
+pub async fn outbox_list_handler(
+    State(dispatcher): State<WebhookDispatcher>,
+) -> Result<Json<Vec<OutboxEntryDto>>, (StatusCode, Json<ErrorDto>)> {
+    let entries = dispatcher
+        .outbox()?
+        .list()
+        .await
+        .map_err(|e| MegaphoneError::InternalError(format!("Error listing outbox - {e}")))?
+        .into_iter()
+        .map(OutboxEntryDto::from)
+        .collect();
+    Ok(Json(entries))
+}
+
+pub async fn outbox_purge_handler(
+    Query(params): Query<OutboxPurgeParams>,
+    State(dispatcher): State<WebhookDispatcher>,
+) -> Result<Json<OutboxPurgeResDto>, (StatusCode, Json<ErrorDto>)> {
+    let purged = dispatcher.outbox()?.purge(params.webhook.as_deref());
+    Ok(Json(OutboxPurgeResDto { purged }))
+}
+
+pub async fn outbox_entry_delete_handler(
+    Path(entry_id): Path<String>,
+    State(dispatcher): State<WebhookDispatcher>,
+) -> Result<Json<OutboxPurgeResDto>, (StatusCode, Json<ErrorDto>)> {
+    let outbox = dispatcher.outbox()?;
+    if !entry_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(MegaphoneError::BadRequest(format!("Malformed entry id '{entry_id}'")).into());
+    }
+    if !outbox.remove(&entry_id) {
+        return Err(MegaphoneError::NotFound.into());
+    }
+    Ok(Json(OutboxPurgeResDto { purged: 1 }))
+}
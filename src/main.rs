@@ -11,31 +11,41 @@ use axum::{
 };
 
 use axum::routing::{delete, IntoMakeService};
-use futures::TryFutureExt;
+use futures::{FutureExt, TryFutureExt};
 use hyperlocal::{SocketIncoming, UnixServerExt};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 use tokio::try_join;
-
-use megaphone::dto::message::EventDto;
+use tonic::codec::CompressionEncoding;
 
 use crate::core::config::{compose_config, MegaphoneConfig};
+use crate::dto::message::EventDto;
+use crate::grpc::channel_service::MegaphoneChannelService;
+use crate::grpc::server::megaphone::channel_service_server::ChannelServiceServer;
 use crate::grpc::server::megaphone::sync_service_server::SyncServiceServer;
 use crate::grpc::sync_service::MegaphoneSyncService;
 use crate::service::agents_manager_service::AgentsManagerService;
 use crate::service::megaphone_service::{MegaphoneService, CHANNEL_DURATION_METRIC_NAME};
+use crate::shutdown::Shutdown;
 use crate::state::MegaphoneState;
 
 mod core;
+mod dto;
 mod grpc;
 mod http;
 pub mod service;
+mod shutdown;
 mod state;
 
-fn spawn_buffer_cleaner(svc: MegaphoneService<EventDto>) {
+fn spawn_buffer_cleaner(
+    svc: MegaphoneService<EventDto>,
+    interval: Duration,
+    compaction_threshold_bytes: u64,
+) {
     tokio::spawn(async move {
         loop {
-            tokio::time::sleep(Duration::from_secs(10)).await;
+            tokio::time::sleep(interval).await;
             svc.drop_expired();
+            svc.compact_storage(compaction_threshold_bytes);
         }
     });
 }
@@ -64,9 +74,20 @@ async fn main() {
     let address = app_config.address;
     let grpc_address = app_config.grpc_address;
     let mng_socket_path = app_config.mng_socket_path.clone();
+    let cleanup_interval = Duration::from_secs(app_config.cleanup_interval_secs);
+    let compaction_threshold = app_config.storage_compaction_threshold_bytes;
+    let handoff_target = app_config.shutdown_handoff_target.clone();
+    let sync_compression = app_config.sync_compression;
+    let drain_timeout = Duration::from_secs(app_config.shutdown_drain_timeout_secs);
     let service = MegaphoneState::build(app_config).expect("Error building megaphone state");
+    let shutdown = Shutdown::new(&service, handoff_target, sync_compression, drain_timeout);
+    let signaled = shutdown.clone().signaled().shared();
 
-    spawn_buffer_cleaner(FromRef::from_ref(&service));
+    spawn_buffer_cleaner(
+        FromRef::from_ref(&service),
+        cleanup_interval,
+        compaction_threshold,
+    );
 
     let recorder_handle = setup_metrics_recorder();
 
@@ -78,6 +99,18 @@ async fn main() {
         )
         .route("/write-batch", post(http::channel::write_batch_handler))
         .route("/read/:id", get(http::channel::read_handler))
+        .route("/ws/:id", get(http::socket::ws_handler))
+        .route("/ack/:id", post(http::channel::ack_handler))
+        .route("/close/:channel_id", post(http::channel::close_handler))
+        .route("/topic/:name/write", post(http::topic::write_handler))
+        .route(
+            "/subscribe/:channel_id",
+            post(http::topic::subscribe_handler),
+        )
+        .route(
+            "/unsubscribe/:channel_id",
+            post(http::topic::unsubscribe_handler),
+        )
         .route(
             "/channelsExists",
             post(http::channel::channel_exists_handler),
@@ -86,22 +119,38 @@ async fn main() {
         .with_state(service.clone());
 
     let grpc_server = tonic::transport::Server::builder()
-        .add_service(SyncServiceServer::new(MegaphoneSyncService::new(
-            AgentsManagerService::from_ref(&service),
-            MegaphoneService::from_ref(&service),
-        )))
-        .serve(grpc_address);
-
-    try_join!(
-        axum::Server::bind(&address)
-            .serve(app.into_make_service())
-            .map_err(anyhow::Error::from),
-        build_server(mng_socket_path, service)
-            .expect("Error building mgmt server")
-            .map_err(anyhow::Error::from),
-        grpc_server.map_err(anyhow::Error::from),
-    )
-    .expect("Error starting server");
+        .add_service(
+            SyncServiceServer::new(MegaphoneSyncService::new(
+                AgentsManagerService::from_ref(&service),
+                MegaphoneService::from_ref(&service),
+            ))
+            .accept_compressed(CompressionEncoding::Gzip),
+        )
+        .add_service(
+            // Responses are compressed only for clients accepting gzip
+            ChannelServiceServer::new(MegaphoneChannelService::new(MegaphoneService::from_ref(
+                &service,
+            )))
+            .accept_compressed(CompressionEncoding::Gzip)
+            .send_compressed(CompressionEncoding::Gzip),
+        )
+        .serve_with_shutdown(grpc_address, signaled.clone());
+
+    let servers = async {
+        try_join!(
+            axum::Server::bind(&address)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(signaled.clone())
+                .map_err(anyhow::Error::from),
+            build_server(mng_socket_path, service)
+                .expect("Error building mgmt server")
+                .with_graceful_shutdown(signaled)
+                .map_err(anyhow::Error::from),
+            grpc_server.map_err(anyhow::Error::from),
+        )
+        .expect("Error starting server");
+    };
+    shutdown.complete(servers).await;
 }
 
 pub fn build_server(
@@ -121,6 +170,14 @@ pub fn build_server(
             "/channel/:channel_id",
             delete(http::channel::channel_delete_handler),
         )
+        .route(
+            "/webhook/outbox",
+            get(http::webhook::outbox_list_handler).delete(http::webhook::outbox_purge_handler),
+        )
+        .route(
+            "/webhook/outbox/:entry_id",
+            delete(http::webhook::outbox_entry_delete_handler),
+        )
         .with_state(service);
 
     let srv = axum::Server::bind_unix(path)?.serve(app.into_make_service());
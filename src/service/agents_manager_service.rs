@@ -8,7 +8,6 @@ use dashmap::mapref::multiple::RefMulti;
 use dashmap::DashMap;
 use lazy_static::lazy_static;
 use megaphone::dto::agent::VirtualAgentModeDto;
-use megaphone::dto::message::EventDto;
 use rand::random;
 use rand::seq::IteratorRandom;
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
@@ -16,7 +15,9 @@ use tokio::sync::mpsc;
 
 use crate::core::config::{AgentConfig, VirtualAgentMode};
 use crate::core::error::MegaphoneError;
-use crate::service::megaphone_service::ChannelShortId;
+use crate::dto::message::EventDto;
+use crate::service::megaphone_service::{ChannelOptions, ChannelShortId, Labels};
+use crate::service::storage::{WalRecord, WalStorage};
 
 #[derive(Debug, Clone)]
 pub struct VirtualAgentProps {
@@ -105,6 +106,7 @@ impl From<VirtualAgentMode> for VirtualAgentStatus {
 pub struct AgentsManagerService {
     warmup_secs: u64,
     virtual_agents: Arc<DashMap<String, VirtualAgentProps>>,
+    storage: Option<Arc<WalStorage>>,
 }
 
 impl Clone for AgentsManagerService {
@@ -112,12 +114,17 @@ impl Clone for AgentsManagerService {
         Self {
             warmup_secs: self.warmup_secs,
             virtual_agents: self.virtual_agents.clone(),
+            storage: self.storage.clone(),
         }
     }
 }
 
 impl AgentsManagerService {
-    pub fn new(conf: AgentConfig, warmup_secs: u64) -> Result<Self, MegaphoneError> {
+    pub fn new(
+        conf: AgentConfig,
+        warmup_secs: u64,
+        storage: Option<Arc<WalStorage>>,
+    ) -> Result<Self, MegaphoneError> {
         let virtual_agents = conf
             .virtual_agents
             .into_iter()
@@ -129,6 +136,7 @@ impl AgentsManagerService {
         Ok(Self {
             warmup_secs,
             virtual_agents: Arc::new(virtual_agents),
+            storage,
         })
     }
 
@@ -177,13 +185,41 @@ impl AgentsManagerService {
 
     pub fn add_master(&self, name: &str) -> Result<(), MegaphoneError> {
         Self::validate_agent_name(name)?;
-        self.virtual_agents.insert(
-            String::from(name),
-            VirtualAgentProps::new(VirtualAgentStatus::Master, self.warmup_secs),
-        );
+        let props = VirtualAgentProps::new(VirtualAgentStatus::Master, self.warmup_secs);
+        let key = props.key;
+        self.virtual_agents.insert(String::from(name), props);
+        if let Some(storage) = &self.storage {
+            storage.append(&WalRecord::AgentKey {
+                name: String::from(name),
+                key,
+            });
+        }
         Ok(())
     }
 
+    /// Keys of the agents owning their channels, producer addresses are ciphered with them.
+    /// Replicas are left out as their key is sent again by the piping agent.
+    pub fn agent_keys(&self) -> Vec<WalRecord> {
+        self.virtual_agents
+            .iter()
+            .filter(|entry| !matches!(entry.status(), VirtualAgentStatus::Replica { .. }))
+            .map(|entry| WalRecord::AgentKey {
+                name: entry.key().clone(),
+                key: entry.key,
+            })
+            .collect()
+    }
+
+    /// Restore the key of an agent, agents missing from the configuration are added as masters
+    pub fn restore_agent_key(&self, name: &str, key: [u8; 32]) {
+        self.virtual_agents
+            .entry(String::from(name))
+            .and_modify(|props| props.key = key)
+            .or_insert_with(|| {
+                VirtualAgentProps::new_with_key(VirtualAgentStatus::Master, key, self.warmup_secs)
+            });
+    }
+
     pub fn open_replica_session(&self, name: &str, key: [u8; 32]) -> Result<(), MegaphoneError> {
         let mut entry = self
             .virtual_agents
@@ -295,6 +331,15 @@ impl AgentsManagerService {
         Ok(())
     }
 
+    /// Drop the pipes of the agent, pipe sessions end once the queued events are forwarded
+    pub fn close_pipes(&self, name: &str) {
+        if let Some(mut agent) = self.virtual_agents.get_mut(name) {
+            if let VirtualAgentStatus::Piped { .. } = agent.status() {
+                agent.change_status(VirtualAgentStatus::Master);
+            }
+        }
+    }
+
     pub fn encrypt_channel_id(
         &self,
         agent_id: &str,
@@ -377,10 +422,28 @@ impl AgentsManagerService {
     }
 }
 
+#[derive(Clone)]
 pub enum SyncEvent {
-    PipeAgentStart { name: String, key: [u8; 32] },
-    PipeAgentEnd { name: String },
-    ChannelCreated { id: String },
-    ChannelDisposed { id: String },
-    EventReceived { channel: String, event: EventDto },
+    PipeAgentStart {
+        name: String,
+        key: [u8; 32],
+    },
+    PipeAgentEnd {
+        name: String,
+    },
+    ChannelCreated {
+        id: String,
+        options: ChannelOptions,
+        topics: Vec<String>,
+        labels: Labels,
+    },
+    ChannelDisposed {
+        id: String,
+    },
+    EventReceived {
+        channel: String,
+        event: EventDto,
+        expires_at: Option<SystemTime>,
+        idempotency_key: Option<String>,
+    },
 }
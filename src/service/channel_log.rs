@@ -0,0 +1,721 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use metrics::counter;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::service::megaphone_service::{
+    WithEventId, WithPayloadSize, MESSAGES_LOST_METRIC_NAME, MESSAGES_REDELIVERED_METRIC_NAME,
+};
+
+/// Event written into a channel, expired events are no longer delivered
+#[derive(Clone)]
+pub struct ChannelMessage<Event> {
+    pub event: Event,
+    pub expires_at: Option<SystemTime>,
+    /// Producer supplied key, messages repeating a recent key are written only once
+    pub idempotency_key: Option<String>,
+    /// Size of the event payload in bytes, counted against the channel quota
+    pub size: usize,
+}
+
+impl<Event: WithPayloadSize> ChannelMessage<Event> {
+    /// Message expiring after the ttl or at the given instant, whichever comes first
+    pub fn new(event: Event, ttl: Option<Duration>, expires_at: Option<SystemTime>) -> Self {
+        let ttl_expiration = ttl.map(|ttl| SystemTime::now() + ttl);
+        Self {
+            expires_at: match (ttl_expiration, expires_at) {
+                (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                (a, b) => a.or(b),
+            },
+            ..Self::from(event)
+        }
+    }
+}
+
+impl<Event> ChannelMessage<Event> {
+    pub fn with_idempotency_key(self, idempotency_key: Option<String>) -> Self {
+        Self {
+            idempotency_key,
+            ..self
+        }
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl<Event: WithPayloadSize> From<Event> for ChannelMessage<Event> {
+    fn from(event: Event) -> Self {
+        Self {
+            size: event.payload_size(),
+            event,
+            expires_at: None,
+            idempotency_key: None,
+        }
+    }
+}
+
+struct Cursor {
+    seq: u64,
+    attached: usize,
+    last_seen: SystemTime,
+    /// Delivered events waiting for an acknowledgement, with their redelivery instant
+    in_flight: BTreeMap<u64, Instant>,
+    /// Next event delivered again after a rewind, replayed events are not pending anymore
+    replay: Option<u64>,
+}
+
+impl Cursor {
+    /// Sequence number of the oldest event not yet delivered or acknowledged
+    fn floor(&self) -> u64 {
+        self.in_flight.keys().next().copied().unwrap_or(self.seq)
+    }
+}
+
+/// Sequenced buffer of channel events.
+///
+/// Each consumer reads through its own cursor. Events already delivered to every consumer are
+/// retained as replay window, the others are pending delivery.
+pub struct ChannelLog<Event> {
+    entries: VecDeque<(u64, ChannelMessage<Event>)>,
+    next_seq: u64,
+    cursors: HashMap<String, Cursor>,
+    floor: u64,
+    redeliveries: u64,
+}
+
+impl<Event> Default for ChannelLog<Event> {
+    fn default() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            next_seq: 0,
+            cursors: HashMap::new(),
+            floor: 0,
+            redeliveries: 0,
+        }
+    }
+}
+
+impl<Event> ChannelLog<Event> {
+    /// Sequence number of the oldest event not yet delivered to every consumer
+    fn floor(&self) -> u64 {
+        self.cursors
+            .values()
+            .map(Cursor::floor)
+            .min()
+            .unwrap_or(self.floor)
+    }
+
+    /// Number of events delivered again because they were not acknowledged in time
+    pub fn redeliveries(&self) -> u64 {
+        self.redeliveries
+    }
+
+    fn entry(&self, seq: u64) -> Option<&ChannelMessage<Event>> {
+        let idx = self
+            .entries
+            .partition_point(|(entry_seq, _)| *entry_seq < seq);
+        self.entries
+            .get(idx)
+            .filter(|(entry_seq, _)| *entry_seq == seq)
+            .map(|(_, message)| message)
+    }
+
+    fn pending_start(&self) -> usize {
+        let floor = self.floor();
+        self.entries.partition_point(|(seq, _)| *seq < floor)
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.entries.len() - self.pending_start()
+    }
+
+    /// Payload bytes of the retained events
+    pub fn bytes(&self) -> usize {
+        self.entries.iter().map(|(_, message)| message.size).sum()
+    }
+
+    /// Check whether a message of the given size can be appended without exceeding the
+    /// capacity and the byte quota. Replayable events are discarded to free bytes.
+    pub fn make_room(&mut self, capacity: usize, max_bytes: usize, size: usize) -> bool {
+        let mut bytes = self.bytes();
+        let pending_start = self.pending_start();
+        let mut trimmed = 0;
+        while bytes + size > max_bytes && trimmed < pending_start {
+            bytes -= self.entries[trimmed].1.size;
+            trimmed += 1;
+        }
+        self.entries.drain(..trimmed);
+        self.pending_len() < capacity && bytes + size <= max_bytes
+    }
+
+    pub fn push(&mut self, message: ChannelMessage<Event>) {
+        self.entries.push_back((self.next_seq, message));
+        self.next_seq += 1;
+    }
+
+    /// Cursor of the consumer, new consumers start from the oldest pending event. The replay
+    /// window is delivered only when a rewind is requested.
+    fn cursor_mut(&mut self, consumer: &str) -> &mut Cursor {
+        let start = self.floor();
+        self.cursors
+            .entry(String::from(consumer))
+            .or_insert_with(|| Cursor {
+                seq: start,
+                attached: 0,
+                last_seen: SystemTime::now(),
+                in_flight: BTreeMap::new(),
+                replay: None,
+            })
+    }
+
+    /// Register a reading session
+    pub fn attach(&mut self, consumer: &str) {
+        self.cursor_mut(consumer).attached += 1;
+    }
+
+    pub fn detach(&mut self, consumer: &str) {
+        if let Some(cursor) = self.cursors.get_mut(consumer) {
+            cursor.attached = cursor.attached.saturating_sub(1);
+            cursor.last_seen = SystemTime::now();
+        }
+    }
+
+    /// Forget consumers that have not been attached for longer than the given ttl, returns
+    /// whether events they held back are no longer pending
+    pub fn drop_idle_consumers(&mut self, ttl: Duration) -> bool {
+        let floor = self.floor();
+        let deadline = SystemTime::now() - ttl;
+        self.cursors
+            .retain(|_, cursor| cursor.attached > 0 || cursor.last_seen.ge(&deadline));
+        if self.cursors.is_empty() {
+            self.floor = floor;
+        }
+        self.floor() > floor
+    }
+
+    /// Discard pending events whose expiration has passed
+    fn drop_expired_pending(&mut self) {
+        let now = SystemTime::now();
+        let start = self.pending_start();
+        if !self
+            .entries
+            .range(start..)
+            .any(|(_, message)| message.is_expired(now))
+        {
+            return;
+        }
+        let len = self.entries.len();
+        let mut idx = 0;
+        self.entries.retain(|(_, message)| {
+            let keep = idx < start || !message.is_expired(now);
+            idx += 1;
+            keep
+        });
+        let expired = len - self.entries.len();
+        counter!(MESSAGES_LOST_METRIC_NAME, "reason" => "expired").increment(expired as u64);
+    }
+
+    /// Deliver the next event accepted by the consumer, events not accepted are skipped.
+    ///
+    /// With an ack timeout, delivered events are kept until acknowledged and delivered again
+    /// once the timeout elapses.
+    pub fn pop_pending(
+        &mut self,
+        consumer: &str,
+        replay_size: usize,
+        ack_timeout: Option<Duration>,
+        accept: impl Fn(&Event) -> bool,
+    ) -> Option<Event>
+    where
+        Event: Clone,
+    {
+        let (cursor, replay) = self
+            .cursors
+            .get(consumer)
+            .map(|cursor| (cursor.seq, cursor.replay))?;
+        self.drop_expired_pending();
+        if let Some(ack_timeout) = ack_timeout {
+            if let Some(event) = self.pop_redelivery(consumer, ack_timeout, &accept) {
+                return Some(event);
+            }
+        }
+        let start = replay.unwrap_or(cursor);
+        let idx = self.entries.partition_point(|(seq, _)| *seq < start);
+        let found = self
+            .entries
+            .range(idx..)
+            .find(|(_, message)| accept(&message.event))
+            .map(|(seq, message)| (seq + 1, message.event.clone()));
+        let next = match &found {
+            Some((next, _)) => *next,
+            None => self.entries.back().map_or(cursor, |(seq, _)| seq + 1),
+        };
+        let replayed = found.as_ref().is_some_and(|(next, _)| *next <= cursor);
+        if let Some(cursor) = self.cursors.get_mut(consumer) {
+            cursor.replay = replay.map(|_| next).filter(|next| *next < cursor.seq);
+            cursor.seq = cmp::max(cursor.seq, next);
+            if let (Some(ack_timeout), Some((next, _))) = (ack_timeout, &found) {
+                // Replayed events already acknowledged do not wait for an acknowledgement again
+                let seq = next - 1;
+                if !replayed || cursor.in_flight.contains_key(&seq) {
+                    cursor.in_flight.insert(seq, Instant::now() + ack_timeout);
+                }
+            }
+        }
+        self.trim_replay(replay_size);
+        found.map(|(_, event)| event)
+    }
+
+    /// Deliver again the first event whose acknowledgement timeout has elapsed
+    fn pop_redelivery(
+        &mut self,
+        consumer: &str,
+        ack_timeout: Duration,
+        accept: impl Fn(&Event) -> bool,
+    ) -> Option<Event>
+    where
+        Event: Clone,
+    {
+        let now = Instant::now();
+        let due = self
+            .cursors
+            .get(consumer)?
+            .in_flight
+            .iter()
+            .filter(|(_, redeliver_at)| **redeliver_at <= now)
+            .map(|(seq, _)| *seq)
+            .collect::<Vec<_>>();
+        for seq in due {
+            let event = self
+                .entry(seq)
+                .map(|message| &message.event)
+                .filter(|event| accept(event))
+                .cloned();
+            let cursor = self.cursors.get_mut(consumer)?;
+            match event {
+                Some(event) => {
+                    cursor.in_flight.insert(seq, now + ack_timeout);
+                    self.redeliveries += 1;
+                    counter!(MESSAGES_REDELIVERED_METRIC_NAME).increment(1);
+                    return Some(event);
+                }
+                None => {
+                    cursor.in_flight.remove(&seq);
+                }
+            }
+        }
+        None
+    }
+
+    /// Instant of the next redelivery due for the consumer
+    fn next_redelivery(&self, consumer: &str) -> Option<Instant> {
+        self.cursors
+            .get(consumer)?
+            .in_flight
+            .values()
+            .min()
+            .copied()
+    }
+
+    /// Acknowledge the delivered events matching the predicate, returns the number of acked events
+    pub fn ack(
+        &mut self,
+        consumer: &str,
+        replay_size: usize,
+        predicate: impl Fn(&Event) -> bool,
+    ) -> usize {
+        let Some(cursor) = self.cursors.get(consumer) else {
+            return 0;
+        };
+        let acked = cursor
+            .in_flight
+            .keys()
+            .filter(|seq| {
+                self.entry(**seq)
+                    .is_none_or(|message| predicate(&message.event))
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        if let Some(cursor) = self.cursors.get_mut(consumer) {
+            for seq in &acked {
+                cursor.in_flight.remove(seq);
+            }
+        }
+        self.trim_replay(replay_size);
+        acked.len()
+    }
+
+    pub fn drop_oldest_pending(&mut self) -> Option<Event> {
+        let idx = self.pending_start();
+        self.entries.remove(idx).map(|(_, message)| message.event)
+    }
+
+    /// Keep only pending events matching the predicate, returns the number of discarded events
+    pub fn retain_pending(&mut self, mut keep: impl FnMut(&Event) -> bool) -> usize {
+        let start = self.pending_start();
+        let len = self.entries.len();
+        let mut idx = 0;
+        self.entries.retain(|(_, message)| {
+            let keep_entry = idx < start || keep(&message.event);
+            idx += 1;
+            keep_entry
+        });
+        len - self.entries.len()
+    }
+
+    /// Deliver again the events following the first event matching the predicate. If no event
+    /// matches, the whole replay window is delivered again. Replayed events stay out of the
+    /// pending events, they do not count against the channel capacity.
+    pub fn rewind_after(&mut self, consumer: &str, predicate: impl Fn(&Event) -> bool) -> bool {
+        let (target, found) = match self
+            .entries
+            .iter()
+            .find(|(_, message)| predicate(&message.event))
+        {
+            Some((seq, _)) => (Some(seq + 1), true),
+            None => (self.entries.front().map(|(seq, _)| *seq), false),
+        };
+        if let (Some(target), Some(cursor)) = (target, self.cursors.get_mut(consumer)) {
+            let from = cmp::min(cursor.replay.unwrap_or(cursor.seq), target);
+            cursor.replay = (from < cursor.seq).then_some(from);
+        }
+        found
+    }
+
+    pub fn contains(&self, predicate: impl Fn(&Event) -> bool) -> bool {
+        self.entries
+            .iter()
+            .any(|(_, message)| predicate(&message.event))
+    }
+
+    /// Messages not yet delivered to every consumer, from the oldest
+    pub fn pending_messages(&self) -> impl Iterator<Item = &ChannelMessage<Event>> {
+        self.entries
+            .range(self.pending_start()..)
+            .map(|(_, message)| message)
+    }
+
+    /// Retained messages, from the oldest
+    pub fn messages(&self) -> impl Iterator<Item = &ChannelMessage<Event>> {
+        self.entries.iter().map(|(_, message)| message)
+    }
+
+    /// Last event delivered to each consumer before its oldest unacknowledged one
+    pub fn consumer_positions(&self) -> impl Iterator<Item = (&str, Option<&Event>)> {
+        self.cursors.iter().map(|(consumer, cursor)| {
+            let idx = self
+                .entries
+                .partition_point(|(seq, _)| *seq < cursor.floor());
+            let last = idx
+                .checked_sub(1)
+                .and_then(|idx| self.entries.get(idx))
+                .map(|(_, message)| &message.event);
+            (consumer.as_str(), last)
+        })
+    }
+
+    /// Move the consumer cursor past the first event matching the predicate, as if it had been
+    /// delivered. With `in_flight` the event waits for an acknowledgement and is due for
+    /// redelivery straight away.
+    pub fn restore_cursor(
+        &mut self,
+        consumer: &str,
+        replay_size: usize,
+        in_flight: bool,
+        predicate: impl Fn(&Event) -> bool,
+    ) {
+        let found = self
+            .entries
+            .iter()
+            .find(|(_, message)| predicate(&message.event))
+            .map(|(seq, _)| *seq);
+        let cursor = self.cursor_mut(consumer);
+        if let Some(seq) = found {
+            cursor.seq = cmp::max(cursor.seq, seq + 1);
+            if in_flight {
+                cursor.in_flight.insert(seq, Instant::now());
+            }
+        }
+        self.trim_replay(replay_size);
+    }
+
+    fn trim_replay(&mut self, replay_size: usize) {
+        let excess = self.pending_start().saturating_sub(replay_size);
+        self.entries.drain(..excess);
+    }
+}
+
+/// Channel log shared between producers and consumers, notifying both sides of changes
+pub struct ChannelQueue<Event> {
+    log: Mutex<ChannelLog<Event>>,
+    written: watch::Sender<()>,
+    consumed: watch::Sender<()>,
+}
+
+impl<Event: WithEventId> ChannelLog<Event> {
+    pub fn pending_ids(&self) -> Vec<String> {
+        self.entries
+            .range(self.pending_start()..)
+            .map(|(_, message)| String::from(message.event.event_id()))
+            .collect()
+    }
+}
+
+impl<Event> Default for ChannelQueue<Event> {
+    fn default() -> Self {
+        Self {
+            log: Mutex::new(ChannelLog::default()),
+            written: watch::Sender::new(()),
+            consumed: watch::Sender::new(()),
+        }
+    }
+}
+
+impl<Event> ChannelQueue<Event> {
+    pub fn lock(&self) -> MutexGuard<'_, ChannelLog<Event>> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn notify_written(&self) {
+        self.written.send_replace(());
+    }
+
+    /// Wake the producers waiting for free slots
+    pub fn notify_consumed(&self) {
+        self.consumed.send_replace(());
+    }
+
+    /// Wait for the next event accepted by the consumer until the deadline is reached
+    pub async fn next(
+        &self,
+        consumer: &str,
+        deadline: Option<Instant>,
+        replay_size: usize,
+        ack_timeout: Option<Duration>,
+        accept: impl Fn(&Event) -> bool,
+    ) -> Option<Event>
+    where
+        Event: Clone,
+    {
+        let mut written = self.written.subscribe();
+        loop {
+            let (next, consumed, redelivery) = {
+                let mut log = self.lock();
+                let pending = log.pending_len();
+                let next = log.pop_pending(consumer, replay_size, ack_timeout, &accept);
+                let redelivery = log.next_redelivery(consumer);
+                (next, log.pending_len() < pending, redelivery)
+            };
+            if consumed {
+                self.consumed.send_replace(());
+            }
+            if let Some(event) = next {
+                return Some(event);
+            }
+            let wake_at = match (deadline, redelivery) {
+                (Some(deadline), Some(redelivery)) => Some(cmp::min(deadline, redelivery)),
+                (deadline, redelivery) => deadline.or(redelivery),
+            };
+            let changed = match wake_at {
+                Some(wake_at) => tokio::time::timeout_at(wake_at, written.changed())
+                    .await
+                    .ok(),
+                None => Some(written.changed().await),
+            };
+            match changed {
+                Some(Ok(())) => {}
+                Some(Err(_)) => return None,
+                None if deadline.is_some_and(|deadline| deadline <= Instant::now()) => return None,
+                None => {}
+            }
+        }
+    }
+
+    /// Acknowledge the events delivered to the consumer matching the predicate
+    pub fn ack(
+        &self,
+        consumer: &str,
+        replay_size: usize,
+        predicate: impl Fn(&Event) -> bool,
+    ) -> usize {
+        let acked = self.lock().ack(consumer, replay_size, predicate);
+        if acked > 0 {
+            self.consumed.send_replace(());
+        }
+        acked
+    }
+
+    /// Wait for a free slot and enough bytes to append the event, gives the event back if the timeout is reached
+    pub async fn push_timeout(
+        &self,
+        message: ChannelMessage<Event>,
+        capacity: usize,
+        max_bytes: usize,
+        timeout: Duration,
+    ) -> Result<(), ChannelMessage<Event>> {
+        let deadline = Instant::now() + timeout;
+        let mut consumed = self.consumed.subscribe();
+        loop {
+            {
+                let mut log = self.lock();
+                if log.make_room(capacity, max_bytes, message.size) {
+                    log.push(message);
+                    drop(log);
+                    self.notify_written();
+                    return Ok(());
+                }
+            }
+            let Ok(Ok(())) = tokio::time::timeout_at(deadline, consumed.changed()).await else {
+                return Err(message);
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::dto::message::EventDto;
+
+    fn log_with(bodies: &[&str]) -> ChannelLog<EventDto> {
+        let mut log = ChannelLog::default();
+        for body in bodies {
+            log.push(ChannelMessage::from(EventDto::new(
+                String::from("s"),
+                json!(body),
+            )));
+        }
+        log
+    }
+
+    fn pop_all(log: &mut ChannelLog<EventDto>, consumer: &str) -> Vec<serde_json::Value> {
+        std::iter::from_fn(|| log.pop_pending(consumer, 10, None, |_| true))
+            .map(|event| event.body)
+            .collect()
+    }
+
+    #[test]
+    fn first_consumer_receives_buffered_events() {
+        let mut log = log_with(&["a", "b"]);
+        log.attach("");
+        assert_eq!(pop_all(&mut log, ""), vec![json!("a"), json!("b")]);
+        assert_eq!(log.pending_len(), 0);
+    }
+
+    #[test]
+    fn new_consumer_skips_replay_window() {
+        let mut log = log_with(&["a", "b"]);
+        log.attach("first");
+        pop_all(&mut log, "first");
+
+        log.attach("second");
+        assert!(pop_all(&mut log, "second").is_empty());
+        log.push(ChannelMessage::from(EventDto::new(
+            String::from("s"),
+            json!("c"),
+        )));
+        assert_eq!(pop_all(&mut log, "second"), vec![json!("c")]);
+    }
+
+    #[test]
+    fn evicted_consumer_restarts_from_pending_events() {
+        let mut log = log_with(&["a"]);
+        log.attach("consumer");
+        pop_all(&mut log, "consumer");
+        log.detach("consumer");
+        assert!(!log.drop_idle_consumers(Duration::ZERO));
+
+        log.attach("consumer");
+        assert!(pop_all(&mut log, "consumer").is_empty());
+    }
+
+    #[test]
+    fn dropping_a_lagging_consumer_frees_slots() {
+        let mut log = log_with(&["a", "b"]);
+        log.attach("fast");
+        log.attach("slow");
+        pop_all(&mut log, "fast");
+        log.detach("slow");
+        assert_eq!(log.pending_len(), 2);
+
+        assert!(log.drop_idle_consumers(Duration::ZERO));
+        assert_eq!(log.pending_len(), 0);
+    }
+
+    #[test]
+    fn rewind_delivers_following_events_again() {
+        let mut log = log_with(&["a", "b", "c"]);
+        log.attach("consumer");
+        let first_id = log
+            .pop_pending("consumer", 10, None, |_| true)
+            .unwrap()
+            .event_id;
+        pop_all(&mut log, "consumer");
+
+        assert!(log.rewind_after("consumer", |evt| evt.event_id == first_id));
+        assert_eq!(pop_all(&mut log, "consumer"), vec![json!("b"), json!("c")]);
+        assert!(!log.rewind_after("consumer", |_| false));
+        assert_eq!(
+            pop_all(&mut log, "consumer"),
+            vec![json!("a"), json!("b"), json!("c")]
+        );
+    }
+
+    #[test]
+    fn rewind_keeps_replayed_events_out_of_pending() {
+        let mut log = log_with(&["a", "b"]);
+        log.attach("consumer");
+        pop_all(&mut log, "consumer");
+
+        assert!(!log.rewind_after("consumer", |_| false));
+        assert_eq!(log.pending_len(), 0);
+        assert!(log.make_room(1, 1024, 0));
+        log.push(ChannelMessage::from(EventDto::new(
+            String::from("s"),
+            json!("c"),
+        )));
+        assert_eq!(log.pending_len(), 1);
+        assert_eq!(
+            pop_all(&mut log, "consumer"),
+            vec![json!("a"), json!("b"), json!("c")]
+        );
+        assert_eq!(log.pending_len(), 0);
+    }
+
+    #[test]
+    fn unacked_events_are_delivered_again() {
+        let mut log = log_with(&["a", "b"]);
+        log.attach("consumer");
+        let ack_timeout = Some(Duration::ZERO);
+        let first = log
+            .pop_pending("consumer", 10, ack_timeout, |_| true)
+            .unwrap();
+        assert_eq!(first.body, json!("a"));
+        // The elapsed timeout makes the first event due again before the second one
+        let again = log
+            .pop_pending("consumer", 10, ack_timeout, |_| true)
+            .unwrap();
+        assert_eq!(again.event_id, first.event_id);
+        assert_eq!(log.redeliveries(), 1);
+
+        assert_eq!(
+            log.ack("consumer", 10, |evt| evt.event_id == first.event_id),
+            1
+        );
+        let second = log
+            .pop_pending("consumer", 10, ack_timeout, |_| true)
+            .unwrap();
+        assert_eq!(second.body, json!("b"));
+        assert_eq!(log.pending_len(), 1);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Outcome of the reservation of an idempotency key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyReservation {
+    /// First write with this key, the message must be written
+    Reserved,
+    /// A write with this key already succeeded
+    Completed,
+    /// A write with this key is still waiting for a free slot
+    InProgress,
+}
+
+/// Idempotency keys of the messages written into a channel, completed keys are remembered for
+/// the deduplication window
+#[derive(Default)]
+pub struct IdempotencyKeys {
+    /// Keys of the reserved writes, with a flag telling whether the write succeeded
+    keys: HashMap<String, bool>,
+    completions: VecDeque<(Instant, String)>,
+}
+
+impl IdempotencyKeys {
+    pub fn reserve(&mut self, key: &str, window: Duration) -> KeyReservation {
+        self.forget_expired(window);
+        match self.keys.get(key) {
+            Some(true) => KeyReservation::Completed,
+            Some(false) => KeyReservation::InProgress,
+            None => {
+                self.keys.insert(String::from(key), false);
+                KeyReservation::Reserved
+            }
+        }
+    }
+
+    /// Record the outcome of a reserved write, failed writes release the key so they can be retried
+    pub fn settle(&mut self, key: &str, succeeded: bool) {
+        if !succeeded {
+            self.keys.remove(key);
+            return;
+        }
+        self.keys.insert(String::from(key), true);
+        self.completions
+            .push_back((Instant::now(), String::from(key)));
+    }
+
+    /// Reserve the key on the shared keys, the returned guard settles the write
+    pub fn reserve_guarded(
+        keys: &Arc<Mutex<Self>>,
+        key: &str,
+        window: Duration,
+    ) -> (KeyReservation, KeyGuard) {
+        let reservation = keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .reserve(key, window);
+        let guard = KeyGuard(
+            (reservation == KeyReservation::Reserved).then(|| (String::from(key), keys.clone())),
+        );
+        (reservation, guard)
+    }
+
+    fn forget_expired(&mut self, window: Duration) {
+        let Some(deadline) = Instant::now().checked_sub(window) else {
+            return;
+        };
+        while self
+            .completions
+            .front()
+            .is_some_and(|(since, _)| *since <= deadline)
+        {
+            if let Some((_, key)) = self.completions.pop_front() {
+                self.keys.remove(&key);
+            }
+        }
+    }
+}
+
+/// Reserved idempotency key of a write in progress. A write dropped before being settled, like a
+/// cancelled request waiting for a free slot, releases its key so that it can be retried.
+#[derive(Default)]
+pub struct KeyGuard(Option<(String, Arc<Mutex<IdempotencyKeys>>)>);
+
+impl KeyGuard {
+    /// Record the outcome of the write
+    pub fn settle(mut self, succeeded: bool) {
+        if let Some((key, keys)) = self.0.take() {
+            keys.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .settle(&key, succeeded);
+        }
+    }
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        if let Some((key, keys)) = self.0.take() {
+            keys.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .settle(&key, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn completed_keys_are_not_written_again() {
+        let mut keys = IdempotencyKeys::default();
+        assert_eq!(keys.reserve("k", WINDOW), KeyReservation::Reserved);
+        assert_eq!(keys.reserve("k", WINDOW), KeyReservation::InProgress);
+        keys.settle("k", true);
+        assert_eq!(keys.reserve("k", WINDOW), KeyReservation::Completed);
+    }
+
+    #[test]
+    fn failed_writes_release_the_key() {
+        let mut keys = IdempotencyKeys::default();
+        keys.reserve("k", WINDOW);
+        keys.settle("k", false);
+        assert_eq!(keys.reserve("k", WINDOW), KeyReservation::Reserved);
+    }
+
+    #[test]
+    fn completed_keys_expire_after_the_window() {
+        let mut keys = IdempotencyKeys::default();
+        keys.reserve("k", Duration::ZERO);
+        keys.settle("k", true);
+        assert_eq!(keys.reserve("k", Duration::ZERO), KeyReservation::Reserved);
+    }
+
+    #[test]
+    fn dropped_guard_releases_the_key() {
+        let keys = Arc::new(Mutex::new(IdempotencyKeys::default()));
+        let (reservation, guard) = IdempotencyKeys::reserve_guarded(&keys, "k", WINDOW);
+        assert_eq!(reservation, KeyReservation::Reserved);
+        let (reservation, _) = IdempotencyKeys::reserve_guarded(&keys, "k", WINDOW);
+        assert_eq!(reservation, KeyReservation::InProgress);
+        drop(guard);
+
+        let (reservation, guard) = IdempotencyKeys::reserve_guarded(&keys, "k", WINDOW);
+        assert_eq!(reservation, KeyReservation::Reserved);
+        guard.settle(true);
+        let (reservation, _) = IdempotencyKeys::reserve_guarded(&keys, "k", WINDOW);
+        assert_eq!(reservation, KeyReservation::Completed);
+    }
+}
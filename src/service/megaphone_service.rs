@@ -1,7 +1,9 @@
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ops::Add;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, PoisonError};
 use std::time::{Duration, SystemTime};
 
 use dashmap::DashMap;
@@ -9,20 +11,25 @@ use futures::FutureExt;
 use metrics::{counter, histogram};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
-use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch, Mutex, OwnedMutexGuard};
 use tokio::time::Instant;
 
-use crate::core::config::{WebHook, WebHookType};
+use crate::core::config::{ConsumerMode, MegaphoneConfig, OverflowPolicy, WebHookType};
+use crate::dto::message::{EventDto, END_OF_STREAM_ID};
 use megaphone::dto::channel::MessageDeliveryFailure;
-use megaphone::dto::message::EventDto;
-use megaphone::model::constants::protocols;
 use megaphone::model::feature::Feature;
 use serde_json::json;
 
 use crate::core::error::MegaphoneError;
+use crate::core::label_selector::LabelSelector;
+use crate::core::protocols;
+use crate::core::stream_filter::StreamFilter;
 use crate::service::agents_manager_service::{AgentsManagerService, SyncEvent};
+use crate::service::channel_log::{ChannelLog, ChannelMessage, ChannelQueue};
+use crate::service::idempotency::{IdempotencyKeys, KeyGuard, KeyReservation};
+use crate::service::storage::{WalRecord, WalStorage};
+use crate::service::webhooks::{channel_agent, ChannelHooks, WebhookDispatcher};
 
 pub const CHANNEL_CREATED_METRIC_NAME: &str = "megaphone_channel_created";
 pub const CHANNEL_DISPOSED_METRIC_NAME: &str = "megaphone_channel_disposed";
@@ -31,25 +38,391 @@ pub const MESSAGES_RECEIVED_METRIC_NAME: &str = "megaphone_messages_received";
 pub const MESSAGES_SENT_METRIC_NAME: &str = "megaphone_messages_read";
 pub const MESSAGES_UNROUTABLE_METRIC_NAME: &str = "megaphone_messages_unroutable";
 pub const MESSAGES_LOST_METRIC_NAME: &str = "megaphone_messages_lost";
+pub const MESSAGES_REDELIVERED_METRIC_NAME: &str = "megaphone_messages_redelivered";
+
+/// Metadata attached to a channel at creation
+pub type Labels = BTreeMap<String, String>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelOptions {
+    pub buffer_size: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub write_timeout: Duration,
+    pub channel_ttl: Duration,
+    pub event_ttl: Duration,
+    pub replay_size: usize,
+    pub consumer_mode: ConsumerMode,
+    /// Endpoint receiving the messages sent by consumers, overrides the `on-consumer-message` webhooks
+    pub message_endpoint: Option<String>,
+    /// Stream id patterns delivered to consumers not specifying their own
+    pub streams: Vec<String>,
+    /// Delivered events must be acknowledged by consumers
+    pub ack_mode: bool,
+    /// Delay after which unacknowledged events are delivered again
+    pub visibility_timeout: Duration,
+    /// Maximum payload bytes retained by the channel
+    pub max_bytes: usize,
+}
+
+impl From<&MegaphoneConfig> for ChannelOptions {
+    fn from(value: &MegaphoneConfig) -> Self {
+        Self {
+            buffer_size: value.channel_buffer_size,
+            overflow_policy: value.channel_overflow_policy,
+            write_timeout: Duration::from_secs(value.channel_write_timeout_secs),
+            channel_ttl: Duration::from_secs(value.channel_ttl_secs),
+            event_ttl: Duration::from_secs(value.event_ttl_secs),
+            replay_size: value.channel_replay_size,
+            consumer_mode: value.channel_consumer_mode,
+            message_endpoint: None,
+            streams: Vec::new(),
+            ack_mode: value.channel_ack_mode,
+            visibility_timeout: Duration::from_secs(value.channel_visibility_timeout_secs),
+            max_bytes: value.channel_max_bytes,
+        }
+    }
+}
+
+impl ChannelOptions {
+    fn validate(&self) -> Result<(), MegaphoneError> {
+        if self.buffer_size == 0 {
+            return Err(MegaphoneError::BadRequest(String::from(
+                "buffer size must be greater than zero",
+            )));
+        }
+        if self.channel_ttl.is_zero() || self.event_ttl.is_zero() {
+            return Err(MegaphoneError::BadRequest(String::from(
+                "ttl must be greater than zero",
+            )));
+        }
+        if self.ack_mode && self.visibility_timeout.is_zero() {
+            return Err(MegaphoneError::BadRequest(String::from(
+                "visibility timeout must be greater than zero",
+            )));
+        }
+        if self.max_bytes == 0 {
+            return Err(MegaphoneError::BadRequest(String::from(
+                "max bytes must be greater than zero",
+            )));
+        }
+        Ok(())
+    }
+
+    fn ack_timeout(&self) -> Option<Duration> {
+        Some(self.visibility_timeout).filter(|_| self.ack_mode)
+    }
+}
+
+pub struct ChannelStats {
+    /// Events delivered again because they were not acknowledged in time
+    pub redeliveries: u64,
+    /// Payload bytes currently retained
+    pub bytes: usize,
+}
+
+#[derive(Default)]
+pub struct ReadOptions {
+    /// Resume reading right after this event
+    pub after: Option<String>,
+    /// Consumer identifier, fan-out channels keep a distinct cursor for each consumer
+    pub consumer: Option<String>,
+    /// Stream id patterns to deliver, overrides the channel ones
+    pub streams: Vec<String>,
+}
+
+struct ReadActivity {
+    readers: AtomicUsize,
+    last_read: StdMutex<SystemTime>,
+    /// Read sessions opened so far, tells whether a consumer came back during the grace period
+    sessions: AtomicU64,
+    /// The `on-consumer-connected` webhooks were fired and the disconnection is not yet notified
+    online: AtomicBool,
+}
+
+impl ReadActivity {
+    fn new() -> Self {
+        Self {
+            readers: AtomicUsize::new(0),
+            last_read: StdMutex::new(SystemTime::now()),
+            sessions: AtomicU64::new(0),
+            online: AtomicBool::new(false),
+        }
+    }
+
+    fn is_expired(&self, ttl: Duration) -> bool {
+        let last_read = *self
+            .last_read
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.readers.load(Ordering::SeqCst) == 0 && last_read.lt(&(SystemTime::now() - ttl))
+    }
+}
+
+/// Presence webhooks of a channel
+struct PresenceHooks {
+    hooks: Arc<ChannelHooks>,
+    grace: Duration,
+}
+
+impl PresenceHooks {
+    fn fire(&self, hook: WebHookType) {
+        self.hooks.fire(hook, json!({}));
+    }
+
+    /// Notify the disconnection unless a consumer reads again within the grace period, so that
+    /// the gap between two long polls or a client reconnection go unnoticed
+    fn schedule_disconnected(self, activity: Arc<ReadActivity>) {
+        let sessions = activity.sessions.load(Ordering::SeqCst);
+        tokio::spawn(async move {
+            tokio::time::sleep(self.grace).await;
+            if activity.readers.load(Ordering::SeqCst) == 0
+                && activity.sessions.load(Ordering::SeqCst) == sessions
+                && activity.online.swap(false, Ordering::SeqCst)
+            {
+                self.fire(WebHookType::OnConsumerDisconnected);
+            }
+        });
+    }
+}
+
+/// Consumer attached to a channel, the channel does not expire until the session is dropped
+struct ReadSession<Event> {
+    consumer: String,
+    filter: StreamFilter,
+    queue: Arc<ChannelQueue<Event>>,
+    activity: Arc<ReadActivity>,
+    presence: Option<PresenceHooks>,
+    _exclusive_guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl<Event> ReadSession<Event> {
+    fn open(
+        consumer: String,
+        filter: StreamFilter,
+        queue: Arc<ChannelQueue<Event>>,
+        activity: Arc<ReadActivity>,
+        presence: Option<PresenceHooks>,
+        exclusive_guard: Option<OwnedMutexGuard<()>>,
+    ) -> Self {
+        queue.lock().attach(&consumer);
+        activity.sessions.fetch_add(1, Ordering::SeqCst);
+        activity.readers.fetch_add(1, Ordering::SeqCst);
+        if let Some(presence) = &presence {
+            if !activity.online.swap(true, Ordering::SeqCst) {
+                presence.fire(WebHookType::OnConsumerConnected);
+            }
+        }
+        Self {
+            consumer,
+            filter,
+            queue,
+            activity,
+            presence,
+            _exclusive_guard: exclusive_guard,
+        }
+    }
+}
+
+impl<Event> Drop for ReadSession<Event> {
+    fn drop(&mut self) {
+        self.queue.lock().detach(&self.consumer);
+        *self
+            .activity
+            .last_read
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = SystemTime::now();
+        let readers = self.activity.readers.fetch_sub(1, Ordering::SeqCst);
+        if let (1, Some(presence)) = (readers, self.presence.take()) {
+            presence.schedule_disconnected(self.activity.clone());
+        }
+    }
+}
 
 pub struct BufferedChannel<Event> {
     full_id: String,
-    tx: Sender<Event>,
-    rx: Arc<Mutex<Receiver<Event>>>,
-    last_read: Arc<Mutex<SystemTime>>,
+    options: ChannelOptions,
+    labels: Labels,
+    queue: Arc<ChannelQueue<Event>>,
+    reader: Arc<Mutex<()>>,
+    activity: Arc<ReadActivity>,
     created_ts: Arc<Mutex<SystemTime>>,
+    idempotency: Arc<StdMutex<IdempotencyKeys>>,
+    /// Closed by the producer, no more events are accepted
+    closed: AtomicBool,
+    hooks: Arc<ChannelHooks>,
+    /// Ids of the pending events, the `Drop` impl cannot require `WithEventId`
+    pending_ids: fn(&ChannelLog<Event>) -> Vec<String>,
 }
-const EVT_BUFFER_SIZE: usize = 100;
 
 impl<Event> BufferedChannel<Event> {
-    fn new(full_id: &str) -> Self {
-        let (tx, rx) = channel(EVT_BUFFER_SIZE);
+    fn new(
+        full_id: &str,
+        options: ChannelOptions,
+        labels: Labels,
+        webhooks: &WebhookDispatcher,
+    ) -> Self
+    where
+        Event: WithEventId,
+    {
         Self {
             full_id: String::from(full_id),
-            tx,
-            rx: Arc::new(Mutex::new(rx)),
-            last_read: Arc::new(Mutex::new(SystemTime::now())),
+            options,
+            hooks: Arc::new(ChannelHooks::new(full_id, &labels, webhooks)),
+            pending_ids: ChannelLog::pending_ids,
+            labels,
+            queue: Default::default(),
+            reader: Default::default(),
+            activity: Arc::new(ReadActivity::new()),
             created_ts: Arc::new(Mutex::new(SystemTime::now())),
+            idempotency: Default::default(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn ensure_not_closed(&self) -> Result<(), MegaphoneError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(MegaphoneError::ChannelClosed);
+        }
+        Ok(())
+    }
+
+    /// Closed channel whose events, end-of-stream included, were consumed
+    fn is_drained(&self) -> bool {
+        self.closed.load(Ordering::SeqCst) && self.queue.lock().pending_len() == 0
+    }
+
+    /// Mark the channel as closed and append the end-of-stream event regardless of the buffer
+    /// capacity, returns false if the channel was already closed
+    fn close(&self, end_of_stream: ChannelMessage<Event>) -> bool {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self.queue.lock().push(end_of_stream);
+        self.queue.notify_written();
+        true
+    }
+
+    /// Refuse messages larger than the service limit or the channel quota
+    fn check_size(
+        &self,
+        message: &ChannelMessage<Event>,
+        max_message_size: usize,
+    ) -> Result<(), MegaphoneError> {
+        let max = cmp::min(max_message_size, self.options.max_bytes);
+        if message.size > max {
+            return Err(MegaphoneError::MessageTooLarge {
+                size: message.size,
+                max,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reserve the idempotency key of the message, returns `None` if the message was already
+    /// written during the window
+    fn reserve_write(
+        &self,
+        message: &ChannelMessage<Event>,
+        window: Duration,
+    ) -> Result<Option<KeyGuard>, MegaphoneError> {
+        let Some(key) = &message.idempotency_key else {
+            return Ok(Some(KeyGuard::default()));
+        };
+        match IdempotencyKeys::reserve_guarded(&self.idempotency, key, window) {
+            (KeyReservation::Reserved, guard) => Ok(Some(guard)),
+            (KeyReservation::Completed, _) => {
+                log::debug!(
+                    "Skipping repeated write with key '{key}' on '{}'",
+                    self.full_id
+                );
+                Ok(None)
+            }
+            (KeyReservation::InProgress, _) => Err(MegaphoneError::Busy),
+        }
+    }
+}
+
+/// Forward the event to the pipes of the channel agent, waiting for room in the pipe queues until
+/// the write timeout of the channel. Pipes that were closed are skipped.
+async fn forward_to_pipes(
+    pipes: Vec<mpsc::Sender<SyncEvent>>,
+    event: SyncEvent,
+    timeout: Duration,
+) -> Result<(), MegaphoneError> {
+    for pipe in pipes {
+        match tokio::time::timeout(timeout, pipe.send(event.clone())).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => log::error!("Error during event pipe - {err}"),
+            Err(_) => {
+                return Err(MegaphoneError::Timeout {
+                    secs: timeout.as_secs() as usize,
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Body of the `on-channel-deleted` webhook, none if no channel was deleted
+fn deleted_channels_body<'a>(
+    deleted_channels: impl Iterator<Item = &'a (String, Labels)>,
+) -> Option<serde_json::Value> {
+    let (channels, labels): (Vec<_>, HashMap<_, _>) = deleted_channels
+        .map(|(id, labels)| (id, (id, labels)))
+        .unzip();
+    (!channels.is_empty()).then(|| json!({ "channels": channels, "labels": labels }))
+}
+
+/// Buffer the message, a blocking policy waits for free slots until the write timeout of the
+/// channel. Returns false if the overflow policy discarded the message.
+async fn buffer_message<Event: WithTimestamp + WithEventId>(
+    channel: dashmap::mapref::one::Ref<'_, ChannelShortId, BufferedChannel<Event>>,
+    message: ChannelMessage<Event>,
+) -> Result<bool, MegaphoneError> {
+    if channel.options.overflow_policy != OverflowPolicy::Block {
+        return channel.try_write(message);
+    }
+    let queue = channel.queue.clone();
+    let hooks = channel.hooks.clone();
+    let capacity = channel.options.buffer_size;
+    let max_bytes = channel.options.max_bytes;
+    let timeout = channel.options.write_timeout;
+    drop(channel);
+    queue
+        .push_timeout(message, capacity, max_bytes, timeout)
+        .await
+        .map(|()| true)
+        .map_err(|message| {
+            hooks.fire(
+                WebHookType::OnWriteTimeout,
+                json!({
+                    "eventId": message.event.event_id(),
+                    "timeoutSecs": timeout.as_secs(),
+                }),
+            );
+            MegaphoneError::Timeout {
+                secs: timeout.as_secs() as usize,
+            }
+        })
+}
+
+impl<Event> BufferedChannel<Event> {
+    fn stats(&self) -> ChannelStats {
+        let log = self.queue.lock();
+        ChannelStats {
+            redeliveries: log.redeliveries(),
+            bytes: log.bytes(),
+        }
+    }
+
+    /// Report the undelivered events of a channel deleted by a client or by the cleanup task
+    fn report_disposed_messages(&self) {
+        let log = self.queue.lock();
+        counter!(MESSAGES_LOST_METRIC_NAME, "reason" => "channel-disposed")
+            .increment(log.pending_len() as u64);
+        if self.hooks.has(WebHookType::OnMessagesLost) {
+            self.hooks
+                .messages_lost("channel-disposed", (self.pending_ids)(&log));
         }
     }
 }
@@ -64,14 +437,6 @@ impl<Event> Drop for BufferedChannel<Event> {
         } else {
             log::warn!("Could not lock created timestamp during channel dispose");
         }
-
-        if let Ok(mut stream) = self.rx.try_lock() {
-            while let Ok(_msg) = stream.try_recv() {
-                counter!(MESSAGES_LOST_METRIC_NAME).increment(1);
-            }
-        } else {
-            log::warn!("Could not lock receiver during channel dispose");
-        }
     }
 }
 
@@ -95,42 +460,113 @@ impl ChannelShortId {
 }
 
 pub struct MegaphoneService<MessageData> {
-    webhooks: HashMap<String, WebHook>,
+    webhooks: WebhookDispatcher,
+    default_options: ChannelOptions,
     agents_manager: AgentsManagerService,
     buffer: Arc<DashMap<ChannelShortId, BufferedChannel<MessageData>>>,
+    topics: Arc<DashMap<String, HashSet<ChannelShortId>>>,
+    storage: Option<Arc<WalStorage>>,
+    closing: Arc<watch::Sender<bool>>,
+    idempotency_window: Duration,
+    max_message_size: usize,
+    disconnect_grace: Duration,
 }
 
 impl<Evt> Clone for MegaphoneService<Evt> {
     fn clone(&self) -> Self {
         Self {
             webhooks: self.webhooks.clone(),
+            default_options: self.default_options.clone(),
             agents_manager: self.agents_manager.clone(),
             buffer: self.buffer.clone(),
+            topics: self.topics.clone(),
+            storage: self.storage.clone(),
+            closing: self.closing.clone(),
+            idempotency_window: self.idempotency_window,
+            max_message_size: self.max_message_size,
+            disconnect_grace: self.disconnect_grace,
         }
     }
 }
 
 impl<Event> MegaphoneService<Event> {
-    pub fn new(webhooks: HashMap<String, WebHook>, agents_manager: AgentsManagerService) -> Self {
+    pub fn new(
+        webhooks: WebhookDispatcher,
+        default_options: ChannelOptions,
+        agents_manager: AgentsManagerService,
+        storage: Option<Arc<WalStorage>>,
+        idempotency_window: Duration,
+        max_message_size: usize,
+        disconnect_grace: Duration,
+    ) -> Self {
         Self {
             webhooks,
+            default_options,
             agents_manager,
             buffer: Default::default(),
+            topics: Default::default(),
+            storage,
+            closing: Arc::new(watch::Sender::new(false)),
+            idempotency_window,
+            max_message_size,
+            disconnect_grace,
+        }
+    }
+
+    /// Refuse new channels and reads, open read streams are terminated
+    pub fn begin_shutdown(&self) {
+        self.closing.send_replace(true);
+    }
+
+    /// Resolves once the shutdown has begun
+    pub async fn shutting_down(&self) {
+        let mut closing = self.closing.subscribe();
+        let _ = closing.wait_for(|closing| *closing).await;
+    }
+
+    fn ensure_open(&self) -> Result<(), MegaphoneError> {
+        if *self.closing.borrow() {
+            return Err(MegaphoneError::ShuttingDown);
+        }
+        Ok(())
+    }
+
+    /// Append the record to the write-ahead log, if any.
+    /// Must not be called while holding a channel reference, compactions iterate the channels.
+    fn journal(&self, record: WalRecord) {
+        if let Some(storage) = &self.storage {
+            storage.append(&record);
         }
     }
 
+    pub fn default_options(&self) -> ChannelOptions {
+        self.default_options.clone()
+    }
+
     pub async fn create_channel(
         &self,
         supported_protocols: &[String],
-    ) -> Result<(String, String, String, Vec<String>), MegaphoneError> {
-        if !supported_protocols.is_empty()
-            && !supported_protocols.contains(&String::from(protocols::HTTP_STREAM_NDJSON_V1))
-        {
+        options: ChannelOptions,
+        topics: &[String],
+        labels: Labels,
+    ) -> Result<(String, String, String, Vec<String>), MegaphoneError>
+    where
+        Event: WithEventId,
+    {
+        self.ensure_open()?;
+        let protocols = protocols::negotiate(supported_protocols);
+        if protocols.is_empty() {
             return Err(MegaphoneError::BadRequest(format!(
                 "protocol(s) {:?} are not supported",
                 supported_protocols
             )));
         }
+        options.validate()?;
+        if let Some(endpoint) = &options.message_endpoint {
+            self.webhooks.check_endpoint(endpoint)?;
+        }
+        validate_topics(topics)?;
+        validate_labels(&labels)?;
         let vagent_id = self.agents_manager.random_master_id()?.to_string();
 
         let (channel_short_id, channel_full_id) = loop {
@@ -159,29 +595,65 @@ impl<Event> MegaphoneService<Event> {
                 .encrypt_channel_id(&vagent_id, channel_short_id)?
         );
 
-        self.buffer
-            .insert(channel_short_id, BufferedChannel::new(&full_id));
-        Ok((
-            vagent_id,
-            full_id,
-            write_id,
-            vec![String::from(protocols::HTTP_STREAM_NDJSON_V1)],
-        ))
+        let channel =
+            BufferedChannel::new(&full_id, options.clone(), labels.clone(), &self.webhooks);
+        channel.hooks.fire(
+            WebHookType::OnChannelCreated,
+            json!({ "protocols": protocols, "topics": topics }),
+        );
+        self.buffer.insert(channel_short_id, channel);
+        self.add_subscriptions(channel_short_id, topics);
+        self.journal(WalRecord::ChannelCreated {
+            id: full_id.clone(),
+            options,
+            topics: topics.to_vec(),
+            labels,
+        });
+        Ok((vagent_id, full_id, write_id, protocols))
     }
 
-    pub async fn create_channel_with_id(&self, id: &str) -> Result<(), MegaphoneError> {
+    pub async fn create_channel_with_id(
+        &self,
+        id: &str,
+        options: Option<ChannelOptions>,
+        topics: &[String],
+        labels: Labels,
+    ) -> Result<(), MegaphoneError>
+    where
+        Event: WithEventId,
+    {
+        let options = options.unwrap_or_else(|| self.default_options());
+        options.validate()?;
+        validate_topics(topics)?;
+        validate_labels(&labels)?;
         counter!(CHANNEL_CREATED_METRIC_NAME).increment(1);
-        self.buffer
-            .insert(ChannelShortId::from_full_id(id)?, BufferedChannel::new(id));
+        let short_id = ChannelShortId::from_full_id(id)?;
+        self.buffer.insert(
+            short_id,
+            BufferedChannel::new(id, options.clone(), labels.clone(), &self.webhooks),
+        );
+        self.add_subscriptions(short_id, topics);
+        self.journal(WalRecord::ChannelCreated {
+            id: String::from(id),
+            options,
+            topics: topics.to_vec(),
+            labels,
+        });
         Ok(())
     }
 
+    /// Open a read stream on the channel, without timeout the stream lasts until it is dropped
     pub async fn read_channel(
         &self,
         id: String,
-        timeout: Duration,
-    ) -> Result<impl futures::stream::Stream<Item = Event>, MegaphoneError> {
-        let deadline = Instant::now() + timeout;
+        timeout: Option<Duration>,
+        read_options: ReadOptions,
+    ) -> Result<impl futures::stream::Stream<Item = Event>, MegaphoneError>
+    where
+        Event: Clone + WithEventId + WithStreamId,
+    {
+        self.ensure_open()?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
         let Some(channel) = self.buffer.get(&ChannelShortId::from_full_id(&id)?) else {
             return Err(MegaphoneError::NotFound);
         };
@@ -192,32 +664,132 @@ impl<Event> MegaphoneService<Event> {
             );
             return Err(MegaphoneError::NotFound);
         }
-        let Ok(rx_guard) = channel.rx.clone().try_lock_owned() else {
-            log::error!("rx mutex already locked");
-            return Err(MegaphoneError::Busy);
+        if channel.is_drained() {
+            return Err(MegaphoneError::ChannelClosed);
+        }
+        let (consumer, exclusive_guard) = match channel.options.consumer_mode {
+            ConsumerMode::Exclusive => {
+                let Ok(reader_guard) = channel.reader.clone().try_lock_owned() else {
+                    log::error!("reader mutex already locked");
+                    return Err(MegaphoneError::Busy);
+                };
+                (String::new(), Some(reader_guard))
+            }
+            ConsumerMode::FanOut => (fan_out_consumer(read_options.consumer)?, None),
         };
-        let Ok(ts_guard) = channel.last_read.clone().try_lock_owned() else {
-            log::error!("timestamp mutex already locked");
-            return Err(MegaphoneError::Busy);
+        let streams = if read_options.streams.is_empty() {
+            &channel.options.streams
+        } else {
+            &read_options.streams
         };
+        let session = ReadSession::open(
+            consumer,
+            StreamFilter::new(streams),
+            channel.queue.clone(),
+            channel.activity.clone(),
+            self.presence_hooks(&channel),
+            exclusive_guard,
+        );
+        if let Some(after) = read_options.after {
+            let found = session
+                .queue
+                .lock()
+                .rewind_after(&session.consumer, |evt| evt.event_id() == after);
+            if !found {
+                log::debug!("Event '{after}' is not in the replay window of channel '{id}'");
+            }
+        }
+        let replay_size = channel.options.replay_size;
+        let ack_timeout = channel.options.ack_timeout();
+        let journal = self.storage.clone().map(|storage| (storage, id));
+        let closing = self.closing.subscribe();
         Ok(futures::stream::unfold(
-            (rx_guard, ts_guard),
-            move |(mut rx_guard, mut ts_guard)| async move {
-                let next = tokio::time::timeout_at(deadline, rx_guard.recv()).await;
-                match next {
-                    Ok(Some(msg)) => {
-                        counter!(MESSAGES_SENT_METRIC_NAME).increment(1);
-                        Some((msg, (rx_guard, ts_guard)))
-                    }
-                    Ok(None) | Err(_) => {
-                        *ts_guard = SystemTime::now();
-                        None
+            (session, journal, closing, false),
+            move |(session, journal, mut closing, ended)| async move {
+                if ended {
+                    return None;
+                }
+                let msg = tokio::select! {
+                    msg = session.queue.next(
+                        &session.consumer,
+                        deadline,
+                        replay_size,
+                        ack_timeout,
+                        |evt| {
+                            evt.stream_id() == END_OF_STREAM_ID
+                                || session.filter.matches(evt.stream_id())
+                        },
+                    ) => msg?,
+                    _ = closing.wait_for(|closing| *closing) => return None,
+                };
+                if let Some((storage, channel)) = &journal {
+                    storage.append(&WalRecord::EventDelivered {
+                        channel: channel.clone(),
+                        consumer: session.consumer.clone(),
+                        event_id: String::from(msg.event_id()),
+                    });
+                }
+                let ended = msg.stream_id() == END_OF_STREAM_ID;
+                // The end-of-stream event needs no acknowledgement
+                if ended && ack_timeout.is_some() {
+                    session.queue.ack(&session.consumer, replay_size, |evt| {
+                        evt.stream_id() == END_OF_STREAM_ID
+                    });
+                    if let Some((storage, channel)) = &journal {
+                        storage.append(&WalRecord::EventsAcked {
+                            channel: channel.clone(),
+                            consumer: session.consumer.clone(),
+                            event_ids: vec![String::from(msg.event_id())],
+                        });
                     }
                 }
+                counter!(MESSAGES_SENT_METRIC_NAME).increment(1);
+                Some((msg, (session, journal, closing, ended)))
             },
         ))
     }
 
+    /// Acknowledge the delivered events, returns the number of acknowledged events
+    pub fn ack(
+        &self,
+        id: &str,
+        consumer: Option<String>,
+        event_ids: &HashSet<String>,
+    ) -> Result<usize, MegaphoneError>
+    where
+        Event: WithEventId,
+    {
+        let Some(channel) = self.buffer.get(&ChannelShortId::from_full_id(id)?) else {
+            return Err(MegaphoneError::NotFound);
+        };
+        if channel.full_id.ne(id) {
+            return Err(MegaphoneError::NotFound);
+        }
+        if !channel.options.ack_mode {
+            return Err(MegaphoneError::BadRequest(String::from(
+                "channel is not in ack mode",
+            )));
+        }
+        let consumer = match channel.options.consumer_mode {
+            ConsumerMode::Exclusive => String::new(),
+            ConsumerMode::FanOut => fan_out_consumer(consumer)?,
+        };
+        let acked = channel
+            .queue
+            .ack(&consumer, channel.options.replay_size, |evt| {
+                event_ids.contains(evt.event_id())
+            });
+        drop(channel);
+        if acked > 0 {
+            self.journal(WalRecord::EventsAcked {
+                channel: String::from(id),
+                consumer,
+                event_ids: event_ids.iter().cloned().collect(),
+            });
+        }
+        Ok(acked)
+    }
+
     pub fn channel_exists(&self, id: &str) -> bool {
         match self.parse_full_id(id) {
             Ok(channel_id) => self.buffer.contains_key(&channel_id),
@@ -230,78 +802,131 @@ impl<Event> MegaphoneService<Event> {
 
     pub fn drop_expired(&self) {
         let mut deleted_channels = Vec::new();
-        self.buffer.retain(|_channel_id, channel| {
-            let channel_not_expired = channel
-                .last_read
-                .try_lock()
-                .map(|last_read| {
-                    let deadline = SystemTime::now() - Duration::from_secs(60);
-                    last_read.ge(&deadline)
-                })
-                .unwrap_or(true);
-
-            let keep_channel = channel_not_expired
-                || channel
-                    .full_id
-                    .split('.')
-                    .next()
-                    .and_then(|agent_id| self.agents_manager.is_agent_distributed(agent_id).ok())
-                    .unwrap_or_else(|| {
-                        log::warn!(
-                            "Could not determine if agent is distributed for channel '{}'",
-                            channel.full_id
-                        );
-                        false
-                    });
+        let mut deleted_ids = HashSet::new();
+        self.buffer.retain(|channel_id, channel| {
+            let channel_not_expired = !channel.activity.is_expired(channel.options.channel_ttl);
+
+            let keep_channel = !channel.is_drained()
+                && (channel_not_expired
+                    || channel
+                        .full_id
+                        .split('.')
+                        .next()
+                        .and_then(|agent_id| {
+                            self.agents_manager.is_agent_distributed(agent_id).ok()
+                        })
+                        .unwrap_or_else(|| {
+                            log::warn!(
+                                "Could not determine if agent is distributed for channel '{}'",
+                                channel.full_id
+                            );
+                            false
+                        }));
 
             if !keep_channel {
-                deleted_channels.push(channel.full_id.clone());
+                channel.report_disposed_messages();
+                deleted_channels.push((channel.full_id.clone(), channel.labels.clone()));
+                deleted_ids.insert(*channel_id);
+            } else if channel.options.consumer_mode == ConsumerMode::FanOut
+                && channel
+                    .queue
+                    .lock()
+                    .drop_idle_consumers(channel.options.channel_ttl)
+            {
+                channel.queue.notify_consumed();
             }
 
             keep_channel
         });
+        self.drop_subscriptions(&deleted_ids);
+        for (id, _) in &deleted_channels {
+            self.journal(WalRecord::ChannelDeleted { id: id.clone() });
+        }
         self.on_channels_deleted(deleted_channels);
     }
 
-    fn on_channels_deleted(&self, deleted_channels: Vec<String>) {
+    fn on_channels_deleted(&self, deleted_channels: Vec<(String, Labels)>) {
+        let Some(body) = deleted_channels_body(deleted_channels.iter()) else {
+            return;
+        };
         self.webhooks
-            .iter()
+            .webhooks()
             .filter(|(_, webhook)| matches!(webhook.hook, WebHookType::OnChannelDeleted))
             .for_each(|(name, webhook)| {
-                let name = name.clone();
-                let url = webhook.endpoint.clone();
-                let body = json!({
-                    "channels": deleted_channels,
-                });
+                let body = if webhook.agent.is_none() && webhook.selector.is_empty() {
+                    Some(body.clone())
+                } else {
+                    deleted_channels_body(
+                        deleted_channels
+                            .iter()
+                            .filter(|(id, labels)| webhook.accepts(channel_agent(id), labels)),
+                    )
+                };
+                if let Some(body) = body {
+                    self.webhooks.dispatch(name, body)
+                }
+            });
+    }
 
-                tokio::spawn(async move {
-                    let client = reqwest::Client::new();
+    /// Presence webhooks of the channel, none if no such webhook accepts the channel
+    fn presence_hooks(&self, channel: &BufferedChannel<Event>) -> Option<PresenceHooks> {
+        let has_presence_hooks = channel.hooks.has(WebHookType::OnConsumerConnected)
+            || channel.hooks.has(WebHookType::OnConsumerDisconnected);
+        has_presence_hooks.then(|| PresenceHooks {
+            hooks: channel.hooks.clone(),
+            grace: self.disconnect_grace,
+        })
+    }
 
-                    let response = client.post(url).json(&body).send().await;
+    /// Forward a message sent by a consumer to the channel endpoint, or to the
+    /// `on-consumer-message` webhooks if the channel has no endpoint
+    pub fn forward_consumer_message(
+        &self,
+        channel_id: &str,
+        stream_id: &str,
+        body: serde_json::Value,
+    ) -> Result<(), MegaphoneError> {
+        let (message_endpoint, hooks) = self
+            .buffer
+            .get(&self.parse_full_id(channel_id)?)
+            .map(|channel| {
+                (
+                    channel.options.message_endpoint.clone(),
+                    channel.hooks.clone(),
+                )
+            })
+            .ok_or(MegaphoneError::NotFound)?;
 
-                    match response {
-                        Err(err) => log::error!("Error processing webhook '{name}' - {err}"),
-                        Ok(response) => {
-                            if !response.status().is_success() {
-                                log::error!(
-                                    "Error processing webhook '{name}' - {}",
-                                    response.status()
-                                );
-                            }
-                        }
-                    }
-                });
+        if let Some(endpoint) = message_endpoint {
+            let body = json!({
+                "channelId": channel_id,
+                "streamId": stream_id,
+                "body": body,
             });
+            self.webhooks
+                .dispatch_to(format!("channel:{channel_id}"), endpoint, body);
+        } else {
+            hooks.fire(
+                WebHookType::OnConsumerMessage,
+                json!({ "streamId": stream_id, "body": body }),
+            );
+        }
+        Ok(())
     }
 
     pub fn drop_channel(&self, id: &str) -> Result<(), MegaphoneError> {
         match self.parse_full_id(id) {
             Ok(channel_id) => {
-                let Some((_id, _channel)) = self.buffer.remove(&channel_id) else {
+                let Some((_id, channel)) = self.buffer.remove(&channel_id) else {
                     return Err(MegaphoneError::InternalError(format!(
                         "Could not find channel with id {id}"
                     )));
                 };
+                channel.report_disposed_messages();
+                self.drop_subscriptions(&HashSet::from([channel_id]));
+                self.journal(WalRecord::ChannelDeleted {
+                    id: channel.full_id.clone(),
+                });
                 Ok(())
             }
             Err(err) => {
@@ -313,24 +938,136 @@ impl<Event> MegaphoneService<Event> {
         }
     }
 
-    pub fn channel_ids_by_agent<'a>(&'a self, name: &str) -> impl Iterator<Item = String> + 'a {
+    pub fn channels_by_agent<'a>(
+        &'a self,
+        name: &str,
+    ) -> impl Iterator<Item = (String, ChannelOptions, Vec<String>, Labels)> + 'a {
         let agent_prefix = format!("{name}.");
         self.buffer
             .iter()
             .filter(move |channel| channel.full_id.starts_with(&agent_prefix))
-            .map(|channel| channel.full_id.to_string())
+            .map(|channel| {
+                (
+                    channel.full_id.to_string(),
+                    channel.options.clone(),
+                    self.channel_topics(*channel.key()),
+                    channel.labels.clone(),
+                )
+            })
     }
 
-    pub fn list_channels<'a, C>(&'a self, skip: usize, limit: usize) -> anyhow::Result<Vec<C>>
+    /// Messages of the agent channels not yet delivered to every consumer
+    pub fn pending_by_agent(&self, name: &str) -> Vec<(String, Vec<ChannelMessage<Event>>)>
+    where
+        Event: Clone,
+    {
+        let agent_prefix = format!("{name}.");
+        self.buffer
+            .iter()
+            .filter(|channel| channel.full_id.starts_with(&agent_prefix))
+            .map(|channel| {
+                let pending = channel.queue.lock().pending_messages().cloned().collect();
+                (channel.full_id.clone(), pending)
+            })
+            .collect()
+    }
+
+    /// Subscribe the channel to the given topics
+    pub fn subscribe(&self, id: &str, topics: &[String]) -> Result<(), MegaphoneError> {
+        validate_topics(topics)?;
+        let channel_id = self.parse_full_id(id)?;
+        let full_id = self.channel_full_id(channel_id)?;
+        self.add_subscriptions(channel_id, topics);
+        self.journal(WalRecord::Subscribed {
+            id: full_id,
+            topics: topics.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Remove the channel subscriptions to the given topics
+    pub fn unsubscribe(&self, id: &str, topics: &[String]) -> Result<(), MegaphoneError> {
+        let channel_id = self.parse_full_id(id)?;
+        let full_id = self.channel_full_id(channel_id)?;
+        for topic in topics {
+            if let Some(mut subscribers) = self.topics.get_mut(topic) {
+                subscribers.remove(&channel_id);
+            }
+            self.topics
+                .remove_if(topic, |_, subscribers| subscribers.is_empty());
+        }
+        self.journal(WalRecord::Unsubscribed {
+            id: full_id,
+            topics: topics.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn channel_full_id(&self, channel_id: ChannelShortId) -> Result<String, MegaphoneError> {
+        self.buffer
+            .get(&channel_id)
+            .map(|channel| channel.full_id.clone())
+            .ok_or(MegaphoneError::NotFound)
+    }
+
+    pub fn channel_topics(&self, channel_id: ChannelShortId) -> Vec<String> {
+        self.topics
+            .iter()
+            .filter(|subscribers| subscribers.contains(&channel_id))
+            .map(|subscribers| subscribers.key().clone())
+            .collect()
+    }
+
+    /// Full ids of the channels subscribed to the topic
+    fn topic_subscribers(&self, topic: &str) -> Vec<String> {
+        self.topics
+            .get(topic)
+            .map(|subscribers| {
+                subscribers
+                    .iter()
+                    .filter_map(|channel_id| self.buffer.get(channel_id))
+                    .map(|channel| channel.full_id.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn add_subscriptions(&self, channel_id: ChannelShortId, topics: &[String]) {
+        for topic in topics {
+            self.topics
+                .entry(topic.clone())
+                .or_default()
+                .insert(channel_id);
+        }
+    }
+
+    fn drop_subscriptions(&self, channel_ids: &HashSet<ChannelShortId>) {
+        if channel_ids.is_empty() {
+            return;
+        }
+        self.topics.retain(|_, subscribers| {
+            subscribers.retain(|channel_id| !channel_ids.contains(channel_id));
+            !subscribers.is_empty()
+        });
+    }
+
+    /// Channels whose labels match the selector, along with their labels and stats
+    pub fn list_channels<'a, C>(
+        &'a self,
+        skip: usize,
+        limit: usize,
+        selector: &LabelSelector,
+    ) -> anyhow::Result<Vec<(C, Labels, ChannelStats)>>
     where
         Event: 'a,
         C: FromStr<Err = anyhow::Error>,
     {
         self.buffer
             .iter()
-            .map(|v| v.full_id.parse::<C>())
+            .filter(|v| selector.matches(&v.labels))
             .skip(skip)
             .take(limit)
+            .map(|v| Ok((v.full_id.parse::<C>()?, v.labels.clone(), v.stats())))
             .collect::<Result<_, _>>()
     }
 
@@ -342,6 +1079,17 @@ impl<Event> MegaphoneService<Event> {
             .count()
     }
 
+    /// Only the encrypted producer address is accepted, the full id is known to consumers too
+    fn parse_producer_address(&self, address: &str) -> Result<ChannelShortId, MegaphoneError> {
+        let (agent_id, channel_id) = address
+            .split_once('.')
+            .filter(|(_, channel_id)| channel_id.len() != 50 && !channel_id.contains('.'))
+            .ok_or_else(|| {
+                MegaphoneError::BadRequest(format!("Malformed producer address '{address}'"))
+            })?;
+        self.agents_manager.decrypt_channel_id(agent_id, channel_id)
+    }
+
     fn parse_full_id(&self, full_id: &str) -> Result<ChannelShortId, MegaphoneError> {
         let mut fragments = full_id.split('.');
         let channel_id = fragments
@@ -356,6 +1104,37 @@ impl<Event> MegaphoneService<Event> {
     }
 }
 
+/// Fan-out consumers need an id of their own, otherwise they would share the same cursor
+fn fan_out_consumer(consumer: Option<String>) -> Result<String, MegaphoneError> {
+    consumer
+        .filter(|consumer| !consumer.is_empty())
+        .ok_or_else(|| {
+            MegaphoneError::BadRequest(String::from("consumer id is required on fan-out channels"))
+        })
+}
+
+fn validate_topics(topics: &[String]) -> Result<(), MegaphoneError> {
+    if topics.iter().any(String::is_empty) {
+        return Err(MegaphoneError::BadRequest(String::from(
+            "topic name must not be empty",
+        )));
+    }
+    Ok(())
+}
+
+fn validate_labels(labels: &Labels) -> Result<(), MegaphoneError> {
+    // Keys are referenced by label selectors
+    if labels
+        .keys()
+        .any(|key| key.is_empty() || key.contains([',', '=', '!']))
+    {
+        return Err(MegaphoneError::BadRequest(String::from(
+            "label keys must not be empty nor contain ',', '=' or '!'",
+        )));
+    }
+    Ok(())
+}
+
 pub trait WithTimestamp {
     fn timestamp(&self) -> SystemTime;
 }
@@ -366,11 +1145,44 @@ impl WithTimestamp for EventDto {
     }
 }
 
+pub trait WithStreamId {
+    fn stream_id(&self) -> &str;
+}
+
+impl WithStreamId for EventDto {
+    fn stream_id(&self) -> &str {
+        &self.stream_id
+    }
+}
+
+pub trait WithPayloadSize {
+    fn payload_size(&self) -> usize;
+}
+
+impl WithPayloadSize for EventDto {
+    fn payload_size(&self) -> usize {
+        match &self.binary {
+            Some(binary) => binary.data.len(),
+            None => serde_json::to_vec(&self.body).map_or(0, |body| body.len()),
+        }
+    }
+}
+
+pub trait WithEventId {
+    fn event_id(&self) -> &str;
+}
+
+impl WithEventId for EventDto {
+    fn event_id(&self) -> &str {
+        &self.event_id
+    }
+}
+
 impl MegaphoneService<EventDto> {
     pub async fn write_batch_into_channels(
         &self,
         ids: &[impl AsRef<str>],
-        messages: Vec<EventDto>,
+        messages: Vec<ChannelMessage<EventDto>>,
     ) -> Vec<MessageDeliveryFailure> {
         let results_fut = ids.iter().map(|chan_id| {
             self.write_batch_into_channel(chan_id.as_ref(), messages.clone())
@@ -395,17 +1207,28 @@ impl MegaphoneService<EventDto> {
             .collect()
     }
 
+    /// Write the event into every channel subscribed to the topic
+    pub async fn write_into_topic(
+        &self,
+        topic: &str,
+        message: ChannelMessage<EventDto>,
+    ) -> Vec<MessageDeliveryFailure> {
+        let subscribers = self.topic_subscribers(topic);
+        self.write_batch_into_channels(&subscribers, vec![message])
+            .await
+    }
+
     async fn write_batch_into_channel(
         &self,
         id: &str,
-        messages: Vec<EventDto>,
+        messages: Vec<ChannelMessage<EventDto>>,
     ) -> Vec<Result<(), MegaphoneError>> {
         let mut results = Vec::with_capacity(messages.len());
         let mut timeout_reached = false;
         for message in messages {
             if !timeout_reached {
                 let result = self.write_into_channel(id, message).await;
-                if let Err(MegaphoneError::Timeout { .. }) = &result {
+                if let Err(MegaphoneError::Timeout { .. } | MegaphoneError::BufferFull) = &result {
                     timeout_reached = true;
                 }
                 results.push(result);
@@ -419,8 +1242,13 @@ impl MegaphoneService<EventDto> {
     pub async fn write_into_channel(
         &self,
         full_id: &str,
-        message: EventDto,
+        message: ChannelMessage<EventDto>,
     ) -> Result<(), MegaphoneError> {
+        if message.event.stream_id == END_OF_STREAM_ID {
+            return Err(MegaphoneError::BadRequest(format!(
+                "stream id '{END_OF_STREAM_ID}' is reserved"
+            )));
+        }
         let channel_id = self.parse_full_id(full_id)?;
 
         let Some(channel) = self.buffer.get(&channel_id) else {
@@ -429,88 +1257,730 @@ impl MegaphoneService<EventDto> {
         };
         counter!(MESSAGES_RECEIVED_METRIC_NAME).increment(1);
 
-        let pipes = channel
-            .full_id
-            .split('.')
-            .next()
-            .map(|agent_id| self.agents_manager.get_pipes(agent_id))
-            .unwrap_or_default();
+        channel.ensure_not_closed()?;
+        channel.check_size(&message, self.max_message_size)?;
+        let Some(key_guard) = channel.reserve_write(&message, self.idempotency_window)? else {
+            return Ok(());
+        };
 
-        for pipe in &pipes {
-            let out = pipe.try_send(SyncEvent::EventReceived {
-                channel: full_id.to_string(),
-                event: message.clone(),
-            });
-            if let Err(err) = out {
-                log::error!("Error during event pipe - {err}");
-            }
-        }
+        let record = self
+            .storage
+            .is_some()
+            .then(|| WalRecord::event_written(&channel.full_id, &message));
+        let pipes = self
+            .agents_manager
+            .get_pipes(channel_agent(&channel.full_id));
 
-        if !pipes.is_empty() {
-            match channel.tx.try_send(message) {
-                Ok(()) => Ok(()),
-                Err(TrySendError::Full(message)) => channel.force_write(message),
-                Err(TrySendError::Closed(_)) => Err(MegaphoneError::InternalError(String::from(
-                    "Channel is closed",
-                ))),
-            }
+        let result = if pipes.is_empty() {
+            buffer_message(channel, message).await
         } else {
-            let tx = channel.tx.clone();
+            // The consumers of a piped agent read from the pipe targets, so the event is forwarded
+            // first and the local copy never waits for free slots
+            let timeout = channel.options.write_timeout;
             drop(channel);
-            tx.send_timeout(message, Duration::from_secs(10))
-                .await
-                .map_err(|err| match err {
-                    SendTimeoutError::Timeout(_) => MegaphoneError::Timeout { secs: 10 },
-                    SendTimeoutError::Closed(_) => {
-                        MegaphoneError::InternalError(String::from("Channel is closed"))
+            let forwarded = SyncEvent::EventReceived {
+                channel: full_id.to_string(),
+                event: message.event.clone(),
+                expires_at: message.expires_at,
+                idempotency_key: message.idempotency_key.clone(),
+            };
+            match forward_to_pipes(pipes, forwarded, timeout).await {
+                Ok(()) => match self.buffer.get(&channel_id) {
+                    Some(channel) => {
+                        channel.overwrite(message);
+                        Ok(true)
                     }
-                })
+                    None => Err(MegaphoneError::NotFound),
+                },
+                Err(err) => Err(err),
+            }
+        };
+        // Messages discarded by the overflow policy may be written again with the same key
+        key_guard.settle(matches!(result, Ok(true)));
+        if let (Ok(true), Some(record)) = (&result, record) {
+            self.journal(record);
         }
+        result.map(|_| ())
     }
 
-    pub fn inject_into_channel(&self, id: &str, message: EventDto) -> Result<(), MegaphoneError> {
+    /// Write an event received from a piped agent, a blocking policy waits for free slots until
+    /// the write timeout of the channel
+    pub async fn inject_into_channel(
+        &self,
+        id: &str,
+        message: ChannelMessage<EventDto>,
+    ) -> Result<(), MegaphoneError> {
         let Some(channel) = self.buffer.get(&ChannelShortId::from_full_id(id)?) else {
             counter!(MESSAGES_UNROUTABLE_METRIC_NAME).increment(1);
             return Err(MegaphoneError::NotFound);
         };
         counter!(MESSAGES_RECEIVED_METRIC_NAME).increment(1);
-        match channel.tx.try_send(message) {
-            Ok(()) => Ok(()),
-            Err(TrySendError::Full(message)) => channel.force_write(message),
-            Err(TrySendError::Closed(_message)) => {
-                log::error!("Error injecting message - channel is disconnected");
-                Err(MegaphoneError::InternalError(String::from(
-                    "Disconnected channel",
-                )))
+        if message.event.stream_id == END_OF_STREAM_ID {
+            let record = self
+                .storage
+                .is_some()
+                .then(|| WalRecord::event_written(&channel.full_id, &message));
+            let closed = channel.close(message);
+            drop(channel);
+            if let (true, Some(record)) = (closed, record) {
+                self.journal(record);
+            }
+            return Ok(());
+        }
+        channel.ensure_not_closed()?;
+        channel.check_size(&message, self.max_message_size)?;
+        let Some(key_guard) = channel.reserve_write(&message, self.idempotency_window)? else {
+            return Ok(());
+        };
+        let record = self
+            .storage
+            .is_some()
+            .then(|| WalRecord::event_written(&channel.full_id, &message));
+        let result = buffer_message(channel, message).await;
+        // Messages discarded by the overflow policy may be written again with the same key
+        key_guard.settle(matches!(result, Ok(true)));
+        if let (Ok(true), Some(record)) = (&result, record) {
+            self.journal(record);
+        }
+        result.map(|_| ())
+    }
+
+    /// Close the channel on behalf of its producer. Consumers receive the buffered events followed
+    /// by the end-of-stream event, then the channel is removed by the next cleanup.
+    pub async fn close_channel(&self, producer_address: &str) -> Result<(), MegaphoneError> {
+        let channel_id = self.parse_producer_address(producer_address)?;
+        let Some(channel) = self.buffer.get(&channel_id) else {
+            return Err(MegaphoneError::NotFound);
+        };
+        let message = ChannelMessage::from(EventDto::end_of_stream());
+        let record = self
+            .storage
+            .is_some()
+            .then(|| WalRecord::event_written(&channel.full_id, &message));
+        let full_id = channel.full_id.clone();
+        let timeout = channel.options.write_timeout;
+        let forwarded = SyncEvent::EventReceived {
+            channel: full_id.clone(),
+            event: message.event.clone(),
+            expires_at: message.expires_at,
+            idempotency_key: message.idempotency_key.clone(),
+        };
+        let closed = channel.close(message);
+        drop(channel);
+        if !closed {
+            log::debug!("Channel '{full_id}' is already closed");
+            return Ok(());
+        }
+        if let Some(record) = record {
+            self.journal(record);
+        }
+        // Only the first close reaches the pipes, repeated closes would end the stream twice
+        let pipes = self.agents_manager.get_pipes(channel_agent(&full_id));
+        forward_to_pipes(pipes, forwarded, timeout).await
+    }
+
+    /// Rebuild the channels from the records of the write-ahead log
+    pub fn restore(&self, records: Vec<WalRecord>) {
+        let count = records.len();
+        // A delivery or an ack may be journaled before the write of its event, it is applied
+        // again right after the write of the event is restored
+        let mut early = HashMap::<(String, String), Vec<WalRecord>>::new();
+        for record in records {
+            let deferred = match &record {
+                WalRecord::EventWritten { channel, event, .. } => early
+                    .remove(&(channel.clone(), event.event_id.clone()))
+                    .unwrap_or_default(),
+                WalRecord::EventDelivered {
+                    channel,
+                    consumer,
+                    event_id,
+                } => {
+                    if !self.restored_event(channel, event_id) {
+                        early
+                            .entry((channel.clone(), event_id.clone()))
+                            .or_default()
+                            .push(WalRecord::EventDelivered {
+                                channel: channel.clone(),
+                                consumer: consumer.clone(),
+                                event_id: event_id.clone(),
+                            });
+                    }
+                    Vec::new()
+                }
+                WalRecord::EventsAcked {
+                    channel,
+                    consumer,
+                    event_ids,
+                } => {
+                    for event_id in event_ids {
+                        if !self.restored_event(channel, event_id) {
+                            early
+                                .entry((channel.clone(), event_id.clone()))
+                                .or_default()
+                                .push(WalRecord::EventsAcked {
+                                    channel: channel.clone(),
+                                    consumer: consumer.clone(),
+                                    event_ids: vec![event_id.clone()],
+                                });
+                        }
+                    }
+                    Vec::new()
+                }
+                _ => Vec::new(),
+            };
+            for record in std::iter::once(record).chain(deferred) {
+                if let Err(err) = self.restore_record(record) {
+                    log::warn!("Error restoring record - {err}");
+                }
+            }
+        }
+        log::info!(
+            "Restored {} channels from {count} records",
+            self.buffer.len()
+        );
+    }
+
+    fn restore_record(&self, record: WalRecord) -> Result<(), MegaphoneError> {
+        match record {
+            WalRecord::AgentKey { name, key } => self.agents_manager.restore_agent_key(&name, key),
+            WalRecord::ChannelCreated {
+                id,
+                options,
+                topics,
+                labels,
+            } => {
+                let short_id = ChannelShortId::from_full_id(&id)?;
+                if !self.buffer.contains_key(&short_id) {
+                    self.buffer.insert(
+                        short_id,
+                        BufferedChannel::new(&id, options, labels, &self.webhooks),
+                    );
+                    self.add_subscriptions(short_id, &topics);
+                }
+            }
+            WalRecord::ChannelDeleted { id } => {
+                let short_id = ChannelShortId::from_full_id(&id)?;
+                self.buffer.remove(&short_id);
+                self.drop_subscriptions(&HashSet::from([short_id]));
+            }
+            WalRecord::Subscribed { id, topics } => {
+                self.add_subscriptions(ChannelShortId::from_full_id(&id)?, &topics)
+            }
+            WalRecord::Unsubscribed { id, topics } => {
+                let short_id = ChannelShortId::from_full_id(&id)?;
+                for topic in topics {
+                    if let Some(mut subscribers) = self.topics.get_mut(&topic) {
+                        subscribers.remove(&short_id);
+                    }
+                    self.topics
+                        .remove_if(&topic, |_, subscribers| subscribers.is_empty());
+                }
+            }
+            WalRecord::EventWritten {
+                channel,
+                event,
+                expires_at,
+            } => {
+                let channel = self.restored_channel(&channel)?;
+                let mut log = channel.queue.lock();
+                if !log.contains(|evt| evt.event_id == event.event_id) {
+                    let message = ChannelMessage {
+                        expires_at,
+                        ..ChannelMessage::from(event)
+                    };
+                    while !log.make_room(
+                        channel.options.buffer_size,
+                        channel.options.max_bytes,
+                        message.size,
+                    ) && log.drop_oldest_pending().is_some()
+                    {}
+                    if message.event.stream_id == END_OF_STREAM_ID {
+                        channel.closed.store(true, Ordering::SeqCst);
+                    }
+                    log.push(message);
+                }
             }
+            WalRecord::EventDelivered {
+                channel,
+                consumer,
+                event_id,
+            } => {
+                let channel = self.restored_channel(&channel)?;
+                channel.queue.lock().restore_cursor(
+                    &consumer,
+                    channel.options.replay_size,
+                    channel.options.ack_mode,
+                    |evt| evt.event_id == event_id,
+                );
+            }
+            WalRecord::EventsAcked {
+                channel,
+                consumer,
+                event_ids,
+            } => {
+                let channel = self.restored_channel(&channel)?;
+                channel
+                    .queue
+                    .lock()
+                    .ack(&consumer, channel.options.replay_size, |evt| {
+                        event_ids.contains(&evt.event_id)
+                    });
+            }
+            WalRecord::ConsumerPosition {
+                channel,
+                consumer,
+                after,
+            } => {
+                let channel = self.restored_channel(&channel)?;
+                channel.queue.lock().restore_cursor(
+                    &consumer,
+                    channel.options.replay_size,
+                    false,
+                    |evt| after.as_ref().is_some_and(|after| evt.event_id.eq(after)),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn restored_event(&self, channel: &str, event_id: &str) -> bool {
+        ChannelShortId::from_full_id(channel)
+            .ok()
+            .and_then(|short_id| self.buffer.get(&short_id))
+            .is_none_or(|channel| {
+                channel
+                    .queue
+                    .lock()
+                    .contains(|evt| evt.event_id == event_id)
+            })
+    }
+
+    fn restored_channel(
+        &self,
+        id: &str,
+    ) -> Result<
+        dashmap::mapref::one::Ref<'_, ChannelShortId, BufferedChannel<EventDto>>,
+        MegaphoneError,
+    > {
+        self.buffer
+            .get(&ChannelShortId::from_full_id(id)?)
+            .ok_or(MegaphoneError::NotFound)
+    }
+
+    /// Wait for the journaled records and the webhook outbox entries to be written
+    pub async fn flush_storage(&self) {
+        if let Some(storage) = &self.storage {
+            storage.flush().await;
+        }
+        self.webhooks.flush_outbox().await;
+    }
+
+    /// Compact the write-ahead log once its last segment exceeds the threshold
+    pub fn compact_storage(&self, threshold_bytes: u64) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        if storage.segment_len() < threshold_bytes {
+            return;
+        }
+        if let Err(err) = storage.compact(|| self.snapshot()) {
+            log::error!("Error compacting storage - {err}");
+        }
+    }
+
+    /// Records rebuilding the current state of agents and channels
+    fn snapshot(&self) -> Vec<WalRecord> {
+        let mut records = self.agents_manager.agent_keys();
+        for channel in self.buffer.iter() {
+            records.push(WalRecord::ChannelCreated {
+                id: channel.full_id.clone(),
+                options: channel.options.clone(),
+                topics: self.channel_topics(*channel.key()),
+                labels: channel.labels.clone(),
+            });
+            let log = channel.queue.lock();
+            records.extend(
+                log.messages()
+                    .map(|message| WalRecord::event_written(&channel.full_id, message)),
+            );
+            records.extend(log.consumer_positions().map(|(consumer, last)| {
+                WalRecord::ConsumerPosition {
+                    channel: channel.full_id.clone(),
+                    consumer: String::from(consumer),
+                    after: last.map(|evt| evt.event_id.clone()),
+                }
+            }));
         }
+        records
     }
 }
 
-impl<Event: WithTimestamp> BufferedChannel<Event> {
-    pub fn force_write(&self, event: Event) -> Result<(), MegaphoneError> {
-        let mut rx = self.rx.try_lock().map_err(|_err| {
-            MegaphoneError::InternalError(String::from("Cannot lock channel rx"))
-        })?;
-        let mut buffered_evts = Vec::with_capacity(EVT_BUFFER_SIZE);
+impl<Event: WithTimestamp + WithEventId> BufferedChannel<Event> {
+    /// Write without waiting for free slots, a full blocking channel rejects the write. Returns
+    /// false if the message was discarded by the drop-newest policy.
+    pub fn try_write(&self, message: ChannelMessage<Event>) -> Result<bool, MegaphoneError> {
+        let mut log = self.queue.lock();
+        if log.make_room(
+            self.options.buffer_size,
+            self.options.max_bytes,
+            message.size,
+        ) {
+            log.push(message);
+        } else {
+            self.hooks.fire(
+                WebHookType::OnBufferFull,
+                json!({
+                    "bufferSize": self.options.buffer_size,
+                    "maxBytes": self.options.max_bytes,
+                    "pending": log.pending_len(),
+                    "bytes": log.bytes(),
+                }),
+            );
+            match self.options.overflow_policy {
+                OverflowPolicy::Reject => return Err(MegaphoneError::BufferFull),
+                OverflowPolicy::DropNewest => {
+                    counter!(MESSAGES_LOST_METRIC_NAME, "reason" => "overflow").increment(1);
+                    self.hooks
+                        .messages_lost("overflow", vec![String::from(message.event.event_id())]);
+                    return Ok(false);
+                }
+                OverflowPolicy::Block => return Err(MegaphoneError::BufferFull),
+                OverflowPolicy::DropOldest => self.force_write(&mut log, message),
+            }
+        }
+        drop(log);
+        self.queue.notify_written();
+        Ok(true)
+    }
+
+    /// Write regardless of the overflow policy, discarding the oldest events of a full buffer
+    fn overwrite(&self, message: ChannelMessage<Event>) {
+        let mut log = self.queue.lock();
+        if log.make_room(
+            self.options.buffer_size,
+            self.options.max_bytes,
+            message.size,
+        ) {
+            log.push(message);
+        } else {
+            self.force_write(&mut log, message);
+        }
+        drop(log);
+        self.queue.notify_written();
+    }
+
+    fn force_write(&self, log: &mut ChannelLog<Event>, message: ChannelMessage<Event>) {
         let now = SystemTime::now();
-        let _skipped = rx.try_recv();
+        let mut overflow = Vec::new();
+        let mut expired = Vec::new();
         // Skip first event to preserve one slot
-        counter!(MESSAGES_LOST_METRIC_NAME).increment(1);
-        while let Ok(evt) = rx.try_recv() {
-            if evt.timestamp().add(Duration::from_secs(60)).gt(&now) {
-                buffered_evts.push(evt);
-            } else {
-                counter!(MESSAGES_LOST_METRIC_NAME).increment(1);
-            }
+        if let Some(evt) = log.drop_oldest_pending() {
+            counter!(MESSAGES_LOST_METRIC_NAME, "reason" => "overflow").increment(1);
+            overflow.push(String::from(evt.event_id()));
         }
-        buffered_evts.push(event);
-        for evt in buffered_evts {
-            let out = self.tx.try_send(evt);
-            if let Err(err) = out {
-                log::error!("Error writing back event to the channel - {err}");
+        log.retain_pending(|evt| {
+            let keep = evt.timestamp().add(self.options.event_ttl).gt(&now);
+            if !keep {
+                expired.push(String::from(evt.event_id()));
             }
+            keep
+        });
+        counter!(MESSAGES_LOST_METRIC_NAME, "reason" => "event-ttl")
+            .increment(expired.len() as u64);
+        // Large messages may need more room than a single slot
+        while !log.make_room(
+            self.options.buffer_size,
+            self.options.max_bytes,
+            message.size,
+        ) {
+            let Some(evt) = log.drop_oldest_pending() else {
+                break;
+            };
+            counter!(MESSAGES_LOST_METRIC_NAME, "reason" => "overflow").increment(1);
+            overflow.push(String::from(evt.event_id()));
         }
-        Ok(())
+        log.push(message);
+        self.hooks.messages_lost("overflow", overflow);
+        self.hooks.messages_lost("event-ttl", expired);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::core::config::AgentConfig;
+
+    const AGENT: &str = "test";
+
+    fn options(overflow_policy: OverflowPolicy) -> ChannelOptions {
+        ChannelOptions {
+            buffer_size: 1,
+            overflow_policy,
+            write_timeout: Duration::from_millis(50),
+            channel_ttl: Duration::from_secs(60),
+            event_ttl: Duration::from_secs(60),
+            replay_size: 0,
+            consumer_mode: ConsumerMode::Exclusive,
+            message_endpoint: None,
+            streams: Vec::new(),
+            ack_mode: false,
+            visibility_timeout: Duration::from_secs(30),
+            max_bytes: 1024,
+        }
+    }
+
+    fn service(storage: Option<Arc<WalStorage>>) -> MegaphoneService<EventDto> {
+        let webhooks = WebhookDispatcher::new(
+            HashMap::new(),
+            Duration::from_secs(1),
+            0,
+            Duration::from_secs(1),
+            &[],
+            None,
+        )
+        .unwrap();
+        let agents_manager =
+            AgentsManagerService::new(AgentConfig::from_str(AGENT).unwrap(), 0, storage.clone())
+                .unwrap();
+        MegaphoneService::new(
+            webhooks,
+            options(OverflowPolicy::Block),
+            agents_manager,
+            storage,
+            Duration::from_secs(60),
+            1024,
+            Duration::ZERO,
+        )
+    }
+
+    async fn channel(svc: &MegaphoneService<EventDto>, overflow_policy: OverflowPolicy) -> String {
+        let (_, full_id, _, _) = svc
+            .create_channel(&[], options(overflow_policy), &[], Labels::new())
+            .await
+            .unwrap();
+        full_id
+    }
+
+    fn message(body: &str) -> ChannelMessage<EventDto> {
+        ChannelMessage::from(EventDto::new(String::from("s"), json!(body)))
+    }
+
+    fn pending_bodies(svc: &MegaphoneService<EventDto>) -> Vec<serde_json::Value> {
+        svc.pending_by_agent(AGENT)
+            .into_iter()
+            .flat_map(|(_, pending)| pending)
+            .map(|message| message.event.body)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn full_blocking_channel_times_out_without_dropping() {
+        let svc = service(None);
+        let full_id = channel(&svc, OverflowPolicy::Block).await;
+        svc.write_into_channel(&full_id, message("first"))
+            .await
+            .unwrap();
+
+        let out = svc.write_into_channel(&full_id, message("second")).await;
+        assert!(matches!(out, Err(MegaphoneError::Timeout { .. })));
+        let out = svc.inject_into_channel(&full_id, message("third")).await;
+        assert!(matches!(out, Err(MegaphoneError::Timeout { .. })));
+        assert_eq!(pending_bodies(&svc), vec![json!("first")]);
+    }
+
+    fn forwarded_bodies(pipe: &mut mpsc::Receiver<SyncEvent>) -> Vec<serde_json::Value> {
+        std::iter::from_fn(|| pipe.try_recv().ok())
+            .filter_map(|event| match event {
+                SyncEvent::EventReceived { event, .. } => Some(event.body),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn piped_writes_do_not_wait_for_the_local_buffer() {
+        let svc = service(None);
+        let full_id = channel(&svc, OverflowPolicy::Block).await;
+        let (tx, mut rx) = mpsc::channel(10);
+        svc.agents_manager.register_pipe(AGENT, tx).unwrap();
+
+        for body in ["first", "second"] {
+            svc.write_into_channel(&full_id, message(body))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            forwarded_bodies(&mut rx),
+            vec![json!("first"), json!("second")]
+        );
+        assert_eq!(pending_bodies(&svc), vec![json!("second")]);
+    }
+
+    #[tokio::test]
+    async fn full_pipe_rejects_the_write() {
+        let svc = service(None);
+        let full_id = channel(&svc, OverflowPolicy::Block).await;
+        // The pipe start event fills the pipe
+        let (tx, mut rx) = mpsc::channel(1);
+        svc.agents_manager.register_pipe(AGENT, tx).unwrap();
+
+        let out = svc.write_into_channel(&full_id, message("first")).await;
+        assert!(matches!(out, Err(MegaphoneError::Timeout { .. })));
+        assert!(forwarded_bodies(&mut rx).is_empty());
+        assert!(pending_bodies(&svc).is_empty());
+    }
+
+    #[tokio::test]
+    async fn repeated_close_is_forwarded_once() {
+        let svc = service(None);
+        let (_, _, producer_address, _) = svc
+            .create_channel(&[], options(OverflowPolicy::Block), &[], Labels::new())
+            .await
+            .unwrap();
+        let (tx, mut rx) = mpsc::channel(10);
+        svc.agents_manager.register_pipe(AGENT, tx).unwrap();
+
+        svc.close_channel(&producer_address).await.unwrap();
+        svc.close_channel(&producer_address).await.unwrap();
+        let ends = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|event| matches!(event, SyncEvent::EventReceived { .. }))
+            .count();
+        assert_eq!(ends, 1);
+    }
+
+    #[tokio::test]
+    async fn fan_out_read_requires_consumer_id() {
+        let svc = service(None);
+        let (_, full_id, _, _) = svc
+            .create_channel(
+                &[],
+                ChannelOptions {
+                    consumer_mode: ConsumerMode::FanOut,
+                    ..options(OverflowPolicy::Block)
+                },
+                &[],
+                Labels::new(),
+            )
+            .await
+            .unwrap();
+
+        let out = svc
+            .read_channel(full_id.clone(), None, ReadOptions::default())
+            .await;
+        assert!(matches!(out, Err(MegaphoneError::BadRequest(_))));
+        let out = svc
+            .read_channel(
+                full_id,
+                None,
+                ReadOptions {
+                    consumer: Some(String::from("consumer")),
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert!(out.is_ok());
+    }
+
+    #[tokio::test]
+    async fn discarded_messages_are_not_journaled() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = WalStorage::open(dir.path(), false).unwrap();
+        let svc = service(Some(Arc::new(storage)));
+        let full_id = channel(&svc, OverflowPolicy::DropNewest).await;
+        svc.write_into_channel(&full_id, message("kept"))
+            .await
+            .unwrap();
+        svc.write_into_channel(&full_id, message("discarded"))
+            .await
+            .unwrap();
+        svc.flush_storage().await;
+
+        assert_eq!(pending_bodies(&svc), vec![json!("kept")]);
+        let (_, records) = WalStorage::open(dir.path(), false).unwrap();
+        let written = records
+            .iter()
+            .filter_map(|record| match record {
+                WalRecord::EventWritten { event, .. } => Some(event.body.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(written, vec![json!("kept")]);
+    }
+
+    #[tokio::test]
+    async fn delivery_journaled_before_the_write_is_restored() {
+        let svc = service(None);
+        let full_id = channel(&svc, OverflowPolicy::Block).await;
+        let event = message("delivered").event;
+        svc.restore(vec![
+            WalRecord::EventDelivered {
+                channel: full_id.clone(),
+                consumer: String::from("consumer"),
+                event_id: event.event_id.clone(),
+            },
+            WalRecord::EventWritten {
+                channel: full_id,
+                event,
+                expires_at: None,
+            },
+        ]);
+
+        assert!(pending_bodies(&svc).is_empty());
+    }
+
+    #[tokio::test]
+    async fn discarded_messages_release_idempotency_key() {
+        let svc = service(None);
+        let full_id = channel(&svc, OverflowPolicy::DropNewest).await;
+        svc.write_into_channel(&full_id, message("kept"))
+            .await
+            .unwrap();
+        let keyed = message("discarded").with_idempotency_key(Some(String::from("key")));
+        svc.write_into_channel(&full_id, keyed).await.unwrap();
+
+        let channel = svc
+            .buffer
+            .get(&ChannelShortId::from_full_id(&full_id).unwrap())
+            .unwrap();
+        let (reservation, _) =
+            IdempotencyKeys::reserve_guarded(&channel.idempotency, "key", Duration::from_secs(60));
+        assert!(matches!(reservation, KeyReservation::Reserved));
+    }
+
+    #[tokio::test]
+    async fn cancelled_write_releases_idempotency_key() {
+        let svc = service(None);
+        let full_id = channel(&svc, OverflowPolicy::Block).await;
+        svc.write_into_channel(&full_id, message("first"))
+            .await
+            .unwrap();
+
+        let keyed = || message("second").with_idempotency_key(Some(String::from("key")));
+        let write = svc.write_into_channel(&full_id, keyed());
+        // The request is dropped while waiting for a free slot
+        assert!(tokio::time::timeout(Duration::from_millis(10), write)
+            .await
+            .is_err());
+
+        let out = svc.write_into_channel(&full_id, keyed()).await;
+        assert!(matches!(out, Err(MegaphoneError::Timeout { .. })));
+    }
+
+    #[test]
+    fn empty_delete_batches_have_no_body() {
+        assert_eq!(deleted_channels_body(std::iter::empty()), None);
+
+        let deleted = [(
+            String::from("a.b"),
+            Labels::from([(String::from("tier"), String::from("gold"))]),
+        )];
+        let body = deleted_channels_body(deleted.iter()).unwrap();
+        assert_eq!(body["channels"], json!(["a.b"]));
+        assert_eq!(body["labels"]["a.b"]["tier"], json!("gold"));
+
+        let filtered = deleted.iter().filter(|(_, labels)| labels.is_empty());
+        assert_eq!(deleted_channels_body(filtered), None);
     }
 }
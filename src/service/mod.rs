@@ -1,2 +1,7 @@
 pub mod agents_manager_service;
+pub mod channel_log;
+pub mod idempotency;
 pub mod megaphone_service;
+pub mod outbox;
+pub mod storage;
+pub mod webhooks;
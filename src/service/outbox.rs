@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+use crate::dto::webhook::OutboxEntryDto;
+
+const ENTRY_EXTENSION: &str = "json";
+
+/// Webhook call waiting to be delivered
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub id: String,
+    pub webhook: String,
+    pub endpoint: String,
+    pub body: Value,
+    pub created_at: SystemTime,
+}
+
+impl OutboxEntry {
+    pub fn new(webhook: &str, endpoint: &str, body: Value) -> Self {
+        let created_at = SystemTime::now();
+        let nanos = created_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let suffix: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+        Self {
+            // Ids sort by creation time
+            id: format!("{nanos:024}-{suffix}"),
+            webhook: String::from(webhook),
+            endpoint: String::from(endpoint),
+            body,
+            created_at,
+        }
+    }
+}
+
+impl From<OutboxEntry> for OutboxEntryDto {
+    fn from(value: OutboxEntry) -> Self {
+        Self {
+            id: value.id,
+            webhook: value.webhook,
+            endpoint: value.endpoint,
+            created_at: value.created_at.into(),
+            body: value.body,
+        }
+    }
+}
+
+enum OutboxCommand {
+    Put(OutboxEntry),
+    Remove(String),
+    /// Replied with the entries written by the previous commands
+    List(oneshot::Sender<io::Result<Vec<OutboxEntry>>>),
+    /// Notified once the previous commands are applied
+    Flush(oneshot::Sender<()>),
+}
+
+/// Webhook calls stored on disk until delivered, one file per call.
+///
+/// Files are written to a temporary path and renamed, so an entry is either complete or missing.
+/// Entries are written, removed and listed by a dedicated thread, in the order they are queued, so
+/// the outbox never blocks the runtime on disk I/O.
+pub struct WebhookOutbox {
+    state: Mutex<OutboxState>,
+}
+
+struct OutboxState {
+    /// Webhook of every entry in the outbox, entries still queued to be written included
+    entries: HashMap<String, String>,
+    writer: mpsc::Sender<OutboxCommand>,
+}
+
+impl OutboxState {
+    fn send(&self, command: OutboxCommand) {
+        if self.writer.send(command).is_err() {
+            log::error!("Error updating the outbox - outbox writer stopped");
+        }
+    }
+}
+
+impl WebhookOutbox {
+    /// Open the outbox stored in the directory, returns the entries left by the previous run
+    pub fn open(dir: &Path, fsync: bool) -> io::Result<(Self, Vec<OutboxEntry>)> {
+        fs::create_dir_all(dir)?;
+        let (tx, rx) = mpsc::channel();
+        let writer = EntryWriter {
+            dir: PathBuf::from(dir),
+            fsync,
+        };
+        thread::Builder::new()
+            .name(String::from("outbox-writer"))
+            .spawn(move || writer.run(rx))?;
+        let entries = list_entries(dir)?;
+        let outbox = Self {
+            state: Mutex::new(OutboxState {
+                entries: entries
+                    .iter()
+                    .map(|entry| (entry.id.clone(), entry.webhook.clone()))
+                    .collect(),
+                writer: tx,
+            }),
+        };
+        Ok((outbox, entries))
+    }
+
+    /// Queue the entry to be written
+    pub fn put(&self, entry: OutboxEntry) {
+        let mut state = self.state();
+        state
+            .entries
+            .insert(entry.id.clone(), entry.webhook.clone());
+        state.send(OutboxCommand::Put(entry));
+    }
+
+    /// Wait for the queued entries to be written and removed
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        self.state().send(OutboxCommand::Flush(tx));
+        let _ = rx.await;
+    }
+
+    /// Whether the entry is still in the outbox, false once delivered or purged
+    pub fn contains(&self, id: &str) -> bool {
+        self.state().entries.contains_key(id)
+    }
+
+    /// Queue the entry to be removed, after the entries queued before it are written. Returns
+    /// false if it was not in the outbox.
+    pub fn remove(&self, id: &str) -> bool {
+        let mut state = self.state();
+        let removed = state.entries.remove(id).is_some();
+        if removed {
+            state.send(OutboxCommand::Remove(String::from(id)));
+        }
+        removed
+    }
+
+    /// Entries sorted by creation time, once the queued entries are written
+    pub async fn list(&self) -> io::Result<Vec<OutboxEntry>> {
+        let (tx, rx) = oneshot::channel();
+        self.state().send(OutboxCommand::List(tx));
+        rx.await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "outbox writer stopped"))?
+    }
+
+    /// Remove every entry, or only those of the given webhook, returns the number of removed
+    /// entries
+    pub fn purge(&self, webhook: Option<&str>) -> usize {
+        let mut state = self.state();
+        let purged = state
+            .entries
+            .iter()
+            .filter(|(_, entry_webhook)| webhook.is_none_or(|webhook| webhook == *entry_webhook))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in &purged {
+            state.entries.remove(id);
+            state.send(OutboxCommand::Remove(id.clone()));
+        }
+        purged.len()
+    }
+
+    fn state(&self) -> MutexGuard<'_, OutboxState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Entries sorted by creation time, malformed files are skipped
+fn list_entries(dir: &Path) -> io::Result<Vec<OutboxEntry>> {
+    let mut entries = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == ENTRY_EXTENSION)
+        })
+        .filter_map(|path| {
+            let entry = fs::read(&path).and_then(|content| Ok(serde_json::from_slice(&content)?));
+            match entry {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    log::warn!("Skipping outbox entry {} - {err}", path.display());
+                    None
+                }
+            }
+        })
+        .collect::<Vec<OutboxEntry>>();
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(entries)
+}
+
+/// Owner of the entry writes, running on its own thread
+struct EntryWriter {
+    dir: PathBuf,
+    fsync: bool,
+}
+
+impl EntryWriter {
+    fn run(self, commands: mpsc::Receiver<OutboxCommand>) {
+        for command in commands {
+            match command {
+                OutboxCommand::Put(entry) => {
+                    if let Err(err) = self.put(&entry) {
+                        log::error!(
+                            "Error storing webhook '{}' call in the outbox - {err}",
+                            entry.webhook
+                        );
+                    }
+                }
+                OutboxCommand::Remove(id) => {
+                    if let Err(err) = remove_entry(&self.dir, &id) {
+                        log::error!("Error removing entry '{id}' from the outbox - {err}");
+                    }
+                }
+                OutboxCommand::List(reply) => {
+                    let _ = reply.send(list_entries(&self.dir));
+                }
+                OutboxCommand::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    fn put(&self, entry: &OutboxEntry) -> io::Result<()> {
+        let path = entry_path(&self.dir, &entry.id);
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&serde_json::to_vec(entry)?)?;
+            if self.fsync {
+                file.sync_all()?;
+            }
+        }
+        fs::rename(tmp_path, path)
+    }
+}
+
+fn remove_entry(dir: &Path, id: &str) -> io::Result<bool> {
+    match fs::remove_file(entry_path(dir, id)) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn entry_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.{ENTRY_EXTENSION}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn ids(entries: &[OutboxEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.id.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn entries_are_listed_in_creation_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (outbox, entries) = WebhookOutbox::open(dir.path(), false).unwrap();
+        assert!(entries.is_empty());
+
+        let created = (0..3)
+            .map(|i| OutboxEntry::new("hook", "http://localhost", json!(i)))
+            .collect::<Vec<_>>();
+        for entry in created.iter().rev() {
+            outbox.put(entry.clone());
+        }
+        outbox.flush().await;
+
+        let (_, entries) = WebhookOutbox::open(dir.path(), false).unwrap();
+        assert_eq!(ids(&entries), ids(&created));
+    }
+
+    #[tokio::test]
+    async fn removed_entries_are_removed_after_being_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let (outbox, _) = WebhookOutbox::open(dir.path(), false).unwrap();
+
+        let entry = OutboxEntry::new("hook", "http://localhost", json!({}));
+        let id = entry.id.clone();
+        outbox.put(entry);
+        outbox.remove(&id);
+        outbox.flush().await;
+
+        assert!(!outbox.contains(&id));
+        assert!(outbox.list().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn queued_entries_are_in_the_outbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let (outbox, _) = WebhookOutbox::open(dir.path(), false).unwrap();
+
+        let entry = OutboxEntry::new("hook", "http://localhost", json!({}));
+        let id = entry.id.clone();
+        outbox.put(entry);
+        // The delivery retries of the call must not see it as purged before it is written
+        assert!(outbox.contains(&id));
+        outbox.remove(&id);
+        assert!(!outbox.contains(&id));
+    }
+
+    #[tokio::test]
+    async fn purge_removes_the_entries_of_the_webhook() {
+        let dir = tempfile::tempdir().unwrap();
+        let (outbox, _) = WebhookOutbox::open(dir.path(), false).unwrap();
+
+        let kept = OutboxEntry::new("kept", "http://localhost", json!({}));
+        outbox.put(kept.clone());
+        outbox.put(OutboxEntry::new("purged", "http://localhost", json!(1)));
+        outbox.put(OutboxEntry::new("purged", "http://localhost", json!(2)));
+
+        // Entries still queued to be written are purged too
+        assert_eq!(outbox.purge(Some("purged")), 2);
+        assert_eq!(ids(&outbox.list().await.unwrap()), vec![kept.id.as_str()]);
+        assert_eq!(outbox.purge(None), 1);
+        assert!(outbox.list().await.unwrap().is_empty());
+    }
+}
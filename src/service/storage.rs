@@ -0,0 +1,368 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::dto::message::EventDto;
+use crate::service::channel_log::ChannelMessage;
+use crate::service::megaphone_service::{ChannelOptions, Labels};
+
+const SEGMENT_PREFIX: &str = "wal-";
+const SEGMENT_EXTENSION: &str = "log";
+
+/// Change of the channels state appended to the write-ahead log
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum WalRecord {
+    AgentKey {
+        name: String,
+        key: [u8; 32],
+    },
+    ChannelCreated {
+        id: String,
+        options: ChannelOptions,
+        topics: Vec<String>,
+        #[serde(default, skip_serializing_if = "Labels::is_empty")]
+        labels: Labels,
+    },
+    ChannelDeleted {
+        id: String,
+    },
+    Subscribed {
+        id: String,
+        topics: Vec<String>,
+    },
+    Unsubscribed {
+        id: String,
+        topics: Vec<String>,
+    },
+    EventWritten {
+        channel: String,
+        event: EventDto,
+        expires_at: Option<SystemTime>,
+    },
+    EventDelivered {
+        channel: String,
+        consumer: String,
+        event_id: String,
+    },
+    EventsAcked {
+        channel: String,
+        consumer: String,
+        event_ids: Vec<String>,
+    },
+    /// Consumer cursor captured by a compaction, placed right after the given event
+    ConsumerPosition {
+        channel: String,
+        consumer: String,
+        after: Option<String>,
+    },
+}
+
+impl WalRecord {
+    pub fn event_written(channel: &str, message: &ChannelMessage<EventDto>) -> Self {
+        Self::EventWritten {
+            channel: String::from(channel),
+            event: message.event.clone(),
+            expires_at: message.expires_at,
+        }
+    }
+}
+
+struct Segment {
+    index: u64,
+    file: File,
+}
+
+impl Segment {
+    fn create(dir: &Path, index: u64) -> io::Result<(Self, u64)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(dir, index))?;
+        let len = file.metadata()?.len();
+        Ok((Self { index, file }, len))
+    }
+}
+
+enum WalCommand {
+    /// Serialized record to append
+    Append(Vec<u8>),
+    /// Serialized snapshot replacing every segment
+    Compact(Vec<u8>),
+    /// Notified once the previous commands are written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Append-only log split into numbered segments.
+///
+/// Records are stored as JSON lines. A compaction writes the current state into a new segment
+/// and removes the previous ones. Files are written by a dedicated thread, so appending a record
+/// never blocks the runtime on disk I/O.
+pub struct WalStorage {
+    writer: Mutex<mpsc::Sender<WalCommand>>,
+    segment_len: Arc<AtomicU64>,
+}
+
+impl WalStorage {
+    /// Open the log stored in the directory, returns the records to replay
+    pub fn open(dir: &Path, fsync: bool) -> io::Result<(Self, Vec<WalRecord>)> {
+        fs::create_dir_all(dir)?;
+        let segments = list_segments(dir)?;
+        let mut records = Vec::new();
+        for (index, path) in &segments {
+            let reader = BufReader::new(File::open(path)?);
+            for (line_no, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(record) => records.push(record),
+                    // A crash may leave the last record truncated
+                    Err(err) => log::warn!(
+                        "Skipping malformed record {} of segment {index} - {err}",
+                        line_no + 1
+                    ),
+                }
+            }
+        }
+        let index = segments.last().map_or(0, |(index, _)| index + 1);
+        let (segment, len) = Segment::create(dir, index)?;
+        let segment_len = Arc::new(AtomicU64::new(len));
+        let (tx, rx) = mpsc::channel();
+        let writer = SegmentWriter {
+            dir: PathBuf::from(dir),
+            fsync,
+            segment,
+            segment_len: segment_len.clone(),
+        };
+        thread::Builder::new()
+            .name(String::from("wal-writer"))
+            .spawn(move || writer.run(rx))?;
+        let storage = Self {
+            writer: Mutex::new(tx),
+            segment_len,
+        };
+        Ok((storage, records))
+    }
+
+    /// Queue the record to be appended, records are written in the order they are appended
+    pub fn append(&self, record: &WalRecord) {
+        match serde_json::to_vec(record) {
+            Ok(buf) => self.send(WalCommand::Append(buf)),
+            Err(err) => log::error!("Error serializing record - {err}"),
+        }
+    }
+
+    /// Size in bytes of the segment being appended
+    pub fn segment_len(&self) -> u64 {
+        self.segment_len.load(Ordering::Relaxed)
+    }
+
+    /// Replace every segment with a new one holding the snapshot records.
+    ///
+    /// Appends wait for the snapshot to be taken, so the snapshot must be taken without
+    /// appending records.
+    pub fn compact(&self, snapshot: impl FnOnce() -> Vec<WalRecord>) -> io::Result<()> {
+        let writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut buf = Vec::new();
+        for record in snapshot() {
+            serde_json::to_writer(&mut buf, &record)?;
+            buf.push(b'\n');
+        }
+        writer
+            .send(WalCommand::Compact(buf))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "wal writer stopped"))
+    }
+
+    /// Wait for the queued records to be written
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        self.send(WalCommand::Flush(tx));
+        let _ = rx.await;
+    }
+
+    fn send(&self, command: WalCommand) {
+        let writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        if writer.send(command).is_err() {
+            log::error!("Error appending record - wal writer stopped");
+        }
+    }
+}
+
+/// Owner of the segment files, running on its own thread
+struct SegmentWriter {
+    dir: PathBuf,
+    fsync: bool,
+    segment: Segment,
+    segment_len: Arc<AtomicU64>,
+}
+
+impl SegmentWriter {
+    fn run(mut self, commands: mpsc::Receiver<WalCommand>) {
+        while let Ok(command) = commands.recv() {
+            // Records queued meanwhile share a single sync
+            let mut pending = vec![command];
+            pending.extend(commands.try_iter());
+            let mut flushed = Vec::new();
+            let mut dirty = false;
+            for command in pending {
+                match command {
+                    WalCommand::Append(buf) => {
+                        if let Err(err) = self.write_record(buf) {
+                            log::error!(
+                                "Error appending record to segment {} - {err}",
+                                self.segment.index
+                            );
+                        }
+                        dirty = true;
+                    }
+                    WalCommand::Compact(buf) => {
+                        if let Err(err) = self.compact(buf) {
+                            log::error!("Error compacting storage - {err}");
+                        }
+                    }
+                    WalCommand::Flush(tx) => flushed.push(tx),
+                }
+            }
+            if dirty && self.fsync {
+                if let Err(err) = self.segment.file.sync_data() {
+                    log::error!("Error syncing segment {} - {err}", self.segment.index);
+                }
+            }
+            for tx in flushed {
+                let _ = tx.send(());
+            }
+        }
+    }
+
+    fn write_record(&mut self, mut buf: Vec<u8>) -> io::Result<()> {
+        buf.push(b'\n');
+        self.segment.file.write_all(&buf)?;
+        self.segment_len
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn compact(&mut self, snapshot: Vec<u8>) -> io::Result<()> {
+        let index = self.segment.index + 1;
+        let tmp_path = segment_path(&self.dir, index).with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&snapshot)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, segment_path(&self.dir, index))?;
+        let (segment, len) = Segment::create(&self.dir, index)?;
+        self.segment = segment;
+        self.segment_len.store(len, Ordering::Relaxed);
+        for (old_index, path) in list_segments(&self.dir)? {
+            if old_index < index {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{SEGMENT_PREFIX}{index:020}.{SEGMENT_EXTENSION}"))
+}
+
+/// Segments stored in the directory, sorted by index
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == SEGMENT_EXTENSION)
+        })
+        .filter_map(|path| {
+            path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.strip_prefix(SEGMENT_PREFIX))
+                .and_then(|index| index.parse().ok())
+                .map(|index| (index, path.clone()))
+        })
+        .collect::<Vec<_>>();
+    segments.sort_by_key(|(index, _)| *index);
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscribed(id: &str) -> WalRecord {
+        WalRecord::Subscribed {
+            id: String::from(id),
+            topics: vec![String::from("topic")],
+        }
+    }
+
+    fn ids(records: &[WalRecord]) -> Vec<&str> {
+        records
+            .iter()
+            .map(|record| match record {
+                WalRecord::Subscribed { id, .. } => id.as_str(),
+                _ => panic!("unexpected record"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replays_appended_records_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, records) = WalStorage::open(dir.path(), true).unwrap();
+        assert!(records.is_empty());
+        storage.append(&subscribed("a"));
+        storage.append(&subscribed("b"));
+        storage.flush().await;
+        assert!(storage.segment_len() > 0);
+        drop(storage);
+
+        let (storage, records) = WalStorage::open(dir.path(), true).unwrap();
+        assert_eq!(ids(&records), vec!["a", "b"]);
+        storage.append(&subscribed("c"));
+        storage.flush().await;
+
+        let (_, records) = WalStorage::open(dir.path(), true).unwrap();
+        assert_eq!(ids(&records), vec!["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn skips_truncated_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = WalStorage::open(dir.path(), false).unwrap();
+        storage.append(&subscribed("a"));
+        storage.flush().await;
+        let (_, path) = list_segments(dir.path()).unwrap().pop().unwrap();
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(br#"{"type":"subscribed","id":"#).unwrap();
+
+        let (_, records) = WalStorage::open(dir.path(), false).unwrap();
+        assert_eq!(ids(&records), vec!["a"]);
+    }
+
+    #[tokio::test]
+    async fn compaction_replaces_previous_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = WalStorage::open(dir.path(), false).unwrap();
+        storage.append(&subscribed("a"));
+        storage.append(&subscribed("b"));
+        storage.compact(|| vec![subscribed("snapshot")]).unwrap();
+        storage.append(&subscribed("c"));
+        storage.flush().await;
+
+        assert_eq!(list_segments(dir.path()).unwrap().len(), 1);
+        let (_, records) = WalStorage::open(dir.path(), false).unwrap();
+        assert_eq!(ids(&records), vec!["snapshot", "c"]);
+    }
+}
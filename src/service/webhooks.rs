@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use bytes::Bytes;
+use metrics::counter;
+use reqwest::{header, StatusCode, Url};
+use ring::hmac;
+use serde_json::{json, Value};
+
+use crate::core::config::{WebHook, WebHookType};
+use crate::core::error::MegaphoneError;
+use crate::service::megaphone_service::Labels;
+use crate::service::outbox::{OutboxEntry, WebhookOutbox};
+
+pub const WEBHOOKS_DELIVERED_METRIC_NAME: &str = "megaphone_webhooks_delivered";
+pub const WEBHOOKS_RETRIED_METRIC_NAME: &str = "megaphone_webhooks_retried";
+pub const WEBHOOKS_FAILED_METRIC_NAME: &str = "megaphone_webhooks_failed";
+
+const SIGNATURE_HEADER: &str = "x-megaphone-signature";
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Bodies of a batch with the outbox entry of each of them
+type Batch = Vec<(Option<String>, Value)>;
+
+/// Virtual agent owning the channel, the first segment of its id
+pub fn channel_agent(full_id: &str) -> &str {
+    full_id.split('.').next().unwrap_or_default()
+}
+
+/// Delivers the webhook calls with a shared http client, retrying failed calls with an
+/// exponential backoff.
+///
+/// With an outbox, calls are stored before being sent and retried until delivered, also across
+/// restarts.
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    client: reqwest::Client,
+    webhooks: Arc<HashMap<String, WebHook>>,
+    max_retries: u32,
+    retry_backoff: Duration,
+    /// Bodies waiting for the batch window of their webhook to elapse, with their outbox entry
+    batches: Arc<Mutex<HashMap<String, Batch>>>,
+    outbox: Option<Arc<WebhookOutbox>>,
+    /// Url prefixes that channels may set as message endpoint
+    endpoint_allowlist: Arc<Vec<Url>>,
+}
+
+impl WebhookDispatcher {
+    pub fn new(
+        webhooks: HashMap<String, WebHook>,
+        timeout: Duration,
+        max_retries: u32,
+        retry_backoff: Duration,
+        endpoint_allowlist: &[String],
+        outbox: Option<WebhookOutbox>,
+    ) -> Result<Self, MegaphoneError> {
+        let endpoint_allowlist = endpoint_allowlist
+            .iter()
+            .map(|endpoint| {
+                Url::parse(endpoint).map_err(|err| {
+                    MegaphoneError::InternalError(format!(
+                        "Invalid allowed message endpoint '{endpoint}' - {err}"
+                    ))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|err| {
+                MegaphoneError::InternalError(format!("Error building webhook client - {err}"))
+            })?;
+        Ok(Self {
+            client,
+            webhooks: Arc::new(webhooks),
+            max_retries,
+            retry_backoff,
+            batches: Default::default(),
+            outbox: outbox.map(Arc::new),
+            endpoint_allowlist: Arc::new(endpoint_allowlist),
+        })
+    }
+
+    /// Refuse message endpoints not matching the allowlist, so that channel creators cannot make
+    /// the server call arbitrary urls
+    pub fn check_endpoint(&self, endpoint: &str) -> Result<(), MegaphoneError> {
+        let not_allowed =
+            || MegaphoneError::BadRequest(format!("message endpoint '{endpoint}' is not allowed"));
+        let endpoint = Url::parse(endpoint).map_err(|_| not_allowed())?;
+        if self
+            .endpoint_allowlist
+            .iter()
+            .any(|allowed| endpoint_matches(allowed, &endpoint))
+        {
+            Ok(())
+        } else {
+            Err(not_allowed())
+        }
+    }
+
+    pub fn webhooks(&self) -> impl Iterator<Item = (&String, &WebHook)> {
+        self.webhooks.iter()
+    }
+
+    /// Call the configured webhook, webhooks with a batch window receive a json array of the
+    /// bodies dispatched during the window
+    pub fn dispatch(&self, name: &str, body: Value) {
+        let Some(webhook) = self.webhooks.get(name) else {
+            log::warn!("Unknown webhook '{name}'");
+            return;
+        };
+        let Some(window) = webhook.batch_window() else {
+            self.spawn_delivery(name.to_string(), webhook.endpoint.clone(), body);
+            return;
+        };
+        let entry_id = self.store(name, &webhook.endpoint, &body);
+        let mut batches = self.batches.lock().unwrap_or_else(PoisonError::into_inner);
+        let batch = batches.entry(name.to_string()).or_default();
+        batch.push((entry_id, body));
+        // The first body of the batch schedules its delivery
+        if batch.len() == 1 {
+            let dispatcher = self.clone();
+            let name = name.to_string();
+            tokio::spawn(async move {
+                tokio::time::sleep(window).await;
+                let batch = dispatcher
+                    .batches
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(&name)
+                    .unwrap_or_default();
+                let (entry_ids, bodies): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+                let endpoint = dispatcher.webhooks[&name].endpoint.clone();
+                let body = Value::from(bodies);
+                // The batch replaces the entries of its bodies
+                let entry_id = dispatcher.store(&name, &endpoint, &body);
+                for entry_id in entry_ids.iter().flatten() {
+                    dispatcher.unstore(entry_id);
+                }
+                dispatcher.deliver(&name, &endpoint, body, entry_id).await;
+            });
+        }
+    }
+
+    /// Call an endpoint that is not a configured webhook, like the channel message endpoint
+    pub fn dispatch_to(&self, name: String, endpoint: String, body: Value) {
+        self.spawn_delivery(name, endpoint, body);
+    }
+
+    /// Deliver the entries left in the outbox by the previous run, single bodies of batched
+    /// webhooks are batched again
+    pub fn replay(&self, entries: Vec<OutboxEntry>) {
+        for entry in entries {
+            let batched = self
+                .webhooks
+                .get(&entry.webhook)
+                .is_some_and(|webhook| webhook.batch_window().is_some());
+            if batched && !entry.body.is_array() {
+                self.unstore(&entry.id);
+                self.dispatch(&entry.webhook, entry.body);
+            } else {
+                let dispatcher = self.clone();
+                tokio::spawn(async move {
+                    dispatcher
+                        .deliver(&entry.webhook, &entry.endpoint, entry.body, Some(entry.id))
+                        .await
+                });
+            }
+        }
+    }
+
+    pub fn outbox(&self) -> Result<&WebhookOutbox, MegaphoneError> {
+        self.outbox.as_deref().ok_or_else(|| {
+            MegaphoneError::BadRequest(String::from("webhook outbox is not enabled"))
+        })
+    }
+
+    fn spawn_delivery(&self, name: String, endpoint: String, body: Value) {
+        let entry_id = self.store(&name, &endpoint, &body);
+        let dispatcher = self.clone();
+        tokio::spawn(async move { dispatcher.deliver(&name, &endpoint, body, entry_id).await });
+    }
+
+    /// Wait for the outbox entries of the dispatched calls to be written
+    pub async fn flush_outbox(&self) {
+        if let Some(outbox) = &self.outbox {
+            outbox.flush().await;
+        }
+    }
+
+    /// Store the call in the outbox, returns the id of the entry
+    fn store(&self, name: &str, endpoint: &str, body: &Value) -> Option<String> {
+        let outbox = self.outbox.as_ref()?;
+        let entry = OutboxEntry::new(name, endpoint, body.clone());
+        let entry_id = entry.id.clone();
+        outbox.put(entry);
+        Some(entry_id)
+    }
+
+    fn unstore(&self, entry_id: &str) {
+        if let Some(outbox) = &self.outbox {
+            outbox.remove(entry_id);
+        }
+    }
+
+    /// Send the call until delivered. Calls stored in the outbox keep being retried after
+    /// `max_retries`, until delivered or purged from the outbox.
+    async fn deliver(&self, name: &str, endpoint: &str, body: Value, entry_id: Option<String>) {
+        let payload = Bytes::from(body.to_string());
+        let signature = self
+            .webhooks
+            .get(name)
+            .and_then(|webhook| webhook.secret.as_ref())
+            .map(|secret| sign(secret, &payload));
+        let stored = |entry_id: &str| {
+            self.outbox
+                .as_ref()
+                .is_some_and(|outbox| outbox.contains(entry_id))
+        };
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            if attempt > 0 {
+                counter!(WEBHOOKS_RETRIED_METRIC_NAME, "webhook" => name.to_string()).increment(1);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                if entry_id
+                    .as_deref()
+                    .is_some_and(|entry_id| !stored(entry_id))
+                {
+                    log::info!("Webhook '{name}' call purged from the outbox");
+                    return;
+                }
+            }
+            let mut request = self
+                .client
+                .post(endpoint)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(payload.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    counter!(WEBHOOKS_DELIVERED_METRIC_NAME, "webhook" => name.to_string())
+                        .increment(1);
+                    break;
+                }
+                Ok(response) if !is_retryable(response.status()) => {
+                    log::error!("Error processing webhook '{name}' - {}", response.status());
+                    counter!(WEBHOOKS_FAILED_METRIC_NAME, "webhook" => name.to_string())
+                        .increment(1);
+                    break;
+                }
+                Ok(response) => {
+                    log::warn!("Error processing webhook '{name}' - {}", response.status())
+                }
+                Err(err) => log::warn!("Error processing webhook '{name}' - {err}"),
+            }
+            if attempt == self.max_retries {
+                counter!(WEBHOOKS_FAILED_METRIC_NAME, "webhook" => name.to_string()).increment(1);
+                if entry_id.is_none() {
+                    log::error!("Webhook '{name}' not delivered");
+                    return;
+                }
+                log::error!("Webhook '{name}' not delivered, retrying from the outbox");
+            }
+            attempt += 1;
+        }
+        if let Some(entry_id) = &entry_id {
+            self.unstore(entry_id);
+        }
+    }
+}
+
+/// Same origin as the allowed url, with the allowed path or a path below it
+fn endpoint_matches(allowed: &Url, endpoint: &Url) -> bool {
+    allowed.scheme() == endpoint.scheme()
+        && allowed.host_str() == endpoint.host_str()
+        && allowed.port_or_known_default() == endpoint.port_or_known_default()
+        && endpoint.username().is_empty()
+        && path_is_under(allowed.path(), endpoint.path())
+}
+
+/// The prefix must end on a path segment boundary, `/messages` does not allow `/messages-admin`
+fn path_is_under(allowed: &str, path: &str) -> bool {
+    path.strip_prefix(allowed)
+        .is_some_and(|rest| rest.is_empty() || allowed.ends_with('/') || rest.starts_with('/'))
+}
+
+/// Client errors are not retried, except for timeouts and rate limiting
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Hex encoded HMAC-SHA256 of the body, prefixed with the algorithm name
+fn sign(secret: &str, payload: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    format!("sha256={}", hex::encode(hmac::sign(&key, payload)))
+}
+
+/// Webhooks accepting a channel, resolved when the channel is created since neither the
+/// configuration nor the channel labels change afterwards
+pub struct ChannelHooks {
+    dispatcher: WebhookDispatcher,
+    body: Value,
+    hooks: Vec<(WebHookType, String)>,
+}
+
+impl ChannelHooks {
+    pub fn new(full_id: &str, labels: &Labels, dispatcher: &WebhookDispatcher) -> Self {
+        let agent = channel_agent(full_id);
+        let hooks = dispatcher
+            .webhooks()
+            .filter(|(_, webhook)| webhook.accepts(agent, labels))
+            .map(|(name, webhook)| (webhook.hook, name.clone()))
+            .collect();
+        Self {
+            dispatcher: dispatcher.clone(),
+            body: json!({
+                "channelId": full_id,
+                "agent": agent,
+                "labels": labels,
+            }),
+            hooks,
+        }
+    }
+
+    pub fn has(&self, hook: WebHookType) -> bool {
+        self.hooks.iter().any(|(hook_type, _)| *hook_type == hook)
+    }
+
+    /// Call the webhooks of the given type with the channel details merged with the fields of
+    /// `extra`
+    pub fn fire(&self, hook: WebHookType, extra: Value) {
+        let mut body = self.body.clone();
+        if let (Value::Object(body), Value::Object(extra)) = (&mut body, extra) {
+            body.extend(extra);
+        }
+        self.hooks
+            .iter()
+            .filter(|(hook_type, _)| *hook_type == hook)
+            .for_each(|(_, name)| self.dispatcher.dispatch(name, body.clone()));
+    }
+
+    pub fn messages_lost(&self, reason: &str, event_ids: Vec<String>) {
+        if event_ids.is_empty() {
+            return;
+        }
+        self.fire(
+            WebHookType::OnMessagesLost,
+            json!({
+                "reason": reason,
+                "count": event_ids.len(),
+                "eventIds": event_ids,
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dispatcher(endpoint_allowlist: &[&str]) -> WebhookDispatcher {
+        let endpoint_allowlist = endpoint_allowlist
+            .iter()
+            .map(|endpoint| String::from(*endpoint))
+            .collect::<Vec<_>>();
+        WebhookDispatcher::new(
+            HashMap::new(),
+            Duration::from_secs(1),
+            0,
+            Duration::from_secs(1),
+            &endpoint_allowlist,
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn message_endpoints_are_refused_without_allowlist() {
+        let dispatcher = dispatcher(&[]);
+        assert!(dispatcher
+            .check_endpoint("http://backend/messages")
+            .is_err());
+    }
+
+    #[test]
+    fn message_endpoint_must_match_allowed_origin_and_path() {
+        let dispatcher = dispatcher(&["https://backend.local/messages"]);
+        assert!(dispatcher
+            .check_endpoint("https://backend.local/messages/chat")
+            .is_ok());
+        assert!(dispatcher
+            .check_endpoint("https://backend.local:443/messages")
+            .is_ok());
+        for endpoint in [
+            "http://backend.local/messages",
+            "https://backend.local.evil.com/messages",
+            "https://backend.local:8443/messages",
+            "https://user@backend.local/messages",
+            "https://backend.local/admin",
+            "https://backend.local/messages-admin",
+            "http://169.254.169.254/latest/meta-data",
+            "not a url",
+        ] {
+            assert!(dispatcher.check_endpoint(endpoint).is_err(), "{endpoint}");
+        }
+    }
+
+    #[test]
+    fn allowed_path_with_trailing_slash_matches_nested_paths() {
+        let dispatcher = dispatcher(&["https://backend.local/hooks/"]);
+        assert!(dispatcher
+            .check_endpoint("https://backend.local/hooks/chat")
+            .is_ok());
+        assert!(dispatcher
+            .check_endpoint("https://backend.local/hooks-admin")
+            .is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use std::future::Future;
+use std::time::Duration;
+
+use axum::extract::FromRef;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+
+use crate::dto::message::EventDto;
+use crate::grpc::pipe::pipe_agent;
+use crate::service::agents_manager_service::{AgentsManagerService, SyncEvent, VirtualAgentStatus};
+use crate::service::megaphone_service::MegaphoneService;
+use crate::state::MegaphoneState;
+
+/// Agent piped to the handoff target, with the task forwarding its events
+type Handoff = (String, JoinHandle<()>);
+
+/// Graceful shutdown triggered by SIGTERM or SIGINT.
+///
+/// Channels creation and reads are refused as soon as the signal is received. When a handoff
+/// target is configured, every master agent is piped to it along with the undelivered events,
+/// once the servers have stopped accepting writes. The whole shutdown is bounded by the drain
+/// timeout, counted from the signal.
+#[derive(Clone)]
+pub struct Shutdown {
+    agents_mgr: AgentsManagerService,
+    channels_mgr: MegaphoneService<EventDto>,
+    handoff_target: Option<String>,
+    sync_compression: bool,
+    drain_timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(
+        state: &MegaphoneState<EventDto>,
+        handoff_target: Option<String>,
+        sync_compression: bool,
+        drain_timeout: Duration,
+    ) -> Self {
+        Self {
+            agents_mgr: FromRef::from_ref(state),
+            channels_mgr: FromRef::from_ref(state),
+            handoff_target,
+            sync_compression,
+            drain_timeout,
+        }
+    }
+
+    /// Wait for a termination signal, then stop accepting channels and reads
+    pub async fn signaled(self) {
+        wait_signal().await;
+        log::info!("Shutdown requested");
+        self.channels_mgr.begin_shutdown();
+    }
+
+    /// Pipe the master agents to the target along with their undelivered events. Must run once
+    /// the servers are stopped, so that no write is forwarded before the pending events.
+    async fn handoff(&self, target: &str) -> Vec<Handoff> {
+        let mut handoffs = Vec::new();
+        let masters = self
+            .agents_mgr
+            .list_agents()
+            .into_iter()
+            .filter(|(_, props)| matches!(props.status(), VirtualAgentStatus::Master))
+            .map(|(name, _)| name);
+
+        for name in masters {
+            let (tx, forwarder) = match pipe_agent(
+                &self.agents_mgr,
+                &self.channels_mgr,
+                &name,
+                target.to_string(),
+                self.sync_compression,
+            )
+            .await
+            {
+                Ok(pipe) => pipe,
+                Err(err) => {
+                    log::error!("Error handing off agent {name} to {target} - {err}");
+                    continue;
+                }
+            };
+            for (channel, messages) in self.channels_mgr.pending_by_agent(&name) {
+                for message in messages {
+                    let out = tx
+                        .send(SyncEvent::EventReceived {
+                            channel: channel.clone(),
+                            event: message.event,
+                            expires_at: message.expires_at,
+                            idempotency_key: message.idempotency_key,
+                        })
+                        .await;
+                    if let Err(err) = out {
+                        log::error!("Error handing off event of channel {channel} - {err}");
+                    }
+                }
+            }
+            log::info!("Agent {name} handed off to {target}");
+            handoffs.push((name, forwarder));
+        }
+        handoffs
+    }
+
+    /// Wait for the servers to stop, then drain. Exits when the drain timeout expires, even if
+    /// open connections or pipes are still holding the servers or the handoff.
+    pub async fn complete(&self, servers: impl Future<Output = ()>) {
+        let deadline = async {
+            self.channels_mgr.shutting_down().await;
+            tokio::time::sleep(self.drain_timeout).await;
+        };
+        tokio::select! {
+            () = async { servers.await; self.drain().await } => {}
+            () = deadline => log::warn!(
+                "Shutdown not completed within {}s",
+                self.drain_timeout.as_secs()
+            ),
+        }
+    }
+
+    /// Hand the agents off, if a target is configured, then close the pipes and wait for the
+    /// queued events to be forwarded and journaled
+    async fn drain(&self) {
+        let handoffs = match &self.handoff_target {
+            Some(target) => self.handoff(target).await,
+            None => Vec::new(),
+        };
+        let forwarders = handoffs
+            .into_iter()
+            .map(|(name, forwarder)| {
+                self.agents_mgr.close_pipes(&name);
+                forwarder
+            })
+            .collect::<Vec<_>>();
+        futures::future::join_all(forwarders).await;
+        self.channels_mgr.flush_storage().await;
+    }
+}
+
+async fn wait_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Error installing SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
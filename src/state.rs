@@ -1,30 +1,91 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::extract::FromRef;
 use tokio::sync::RwLock;
 
-use crate::core::config::MegaphoneConfig;
+use crate::core::config::{MegaphoneConfig, StorageMode};
 use crate::core::error::MegaphoneError;
+use crate::dto::message::EventDto;
 use crate::service::agents_manager_service::AgentsManagerService;
-use crate::service::megaphone_service::MegaphoneService;
+use crate::service::megaphone_service::{ChannelOptions, MegaphoneService};
+use crate::service::outbox::WebhookOutbox;
+use crate::service::storage::WalStorage;
+use crate::service::webhooks::WebhookDispatcher;
 
 pub struct MegaphoneState<Evt> {
     megaphone_cfg: Arc<RwLock<MegaphoneConfig>>,
     megaphone_svc: MegaphoneService<Evt>,
     agents_manager_svc: AgentsManagerService,
+    webhooks: WebhookDispatcher,
 }
 
-impl<Evt> MegaphoneState<Evt> {
+impl MegaphoneState<EventDto> {
+    /// Build the services, channels are restored from the write-ahead log in wal storage mode
     pub fn build(app_config: MegaphoneConfig) -> Result<Self, MegaphoneError> {
-        let agents_manager =
-            AgentsManagerService::new(app_config.agent.clone(), app_config.agent_warmup_secs)?;
+        let (storage, records) = match app_config.storage_mode {
+            StorageMode::Memory => (None, Vec::new()),
+            StorageMode::Wal => {
+                let (storage, records) =
+                    WalStorage::open(&app_config.storage_path, app_config.storage_fsync).map_err(
+                        |err| {
+                            MegaphoneError::InternalError(format!(
+                                "Error opening storage {} - {err}",
+                                app_config.storage_path.display()
+                            ))
+                        },
+                    )?;
+                (Some(Arc::new(storage)), records)
+            }
+        };
+
+        let agents_manager = AgentsManagerService::new(
+            app_config.agent.clone(),
+            app_config.agent_warmup_secs,
+            storage.clone(),
+        )?;
+
+        let (outbox, outbox_entries) = match &app_config.webhook_outbox_path {
+            None => (None, Vec::new()),
+            Some(path) => {
+                let (outbox, entries) = WebhookOutbox::open(path, app_config.storage_fsync)
+                    .map_err(|err| {
+                        MegaphoneError::InternalError(format!(
+                            "Error opening webhook outbox {} - {err}",
+                            path.display()
+                        ))
+                    })?;
+                (Some(outbox), entries)
+            }
+        };
+        let webhooks = WebhookDispatcher::new(
+            app_config.webhooks.clone(),
+            Duration::from_millis(app_config.webhook_timeout_millis),
+            app_config.webhook_max_retries,
+            Duration::from_millis(app_config.webhook_retry_backoff_millis),
+            &app_config.message_endpoint_allowlist,
+            outbox,
+        )?;
+        webhooks.replay(outbox_entries);
+        let megaphone_svc = MegaphoneService::new(
+            webhooks.clone(),
+            ChannelOptions::from(&app_config),
+            agents_manager.clone(),
+            storage,
+            Duration::from_secs(app_config.idempotency_window_secs),
+            app_config.max_message_size_bytes,
+            Duration::from_millis(app_config.consumer_disconnect_grace_millis),
+        );
+        if app_config.storage_mode == StorageMode::Wal {
+            megaphone_svc.restore(records);
+            // Start from a single segment holding the restored state
+            megaphone_svc.compact_storage(0);
+        }
 
         Ok(MegaphoneState {
-            megaphone_svc: MegaphoneService::new(
-                app_config.webhooks.clone(),
-                agents_manager.clone(),
-            ),
+            megaphone_svc,
             agents_manager_svc: agents_manager,
+            webhooks,
             megaphone_cfg: Arc::new(RwLock::new(app_config)),
         })
     }
@@ -36,10 +97,17 @@ impl<Evt> Clone for MegaphoneState<Evt> {
             agents_manager_svc: self.agents_manager_svc.clone(),
             megaphone_cfg: self.megaphone_cfg.clone(),
             megaphone_svc: self.megaphone_svc.clone(),
+            webhooks: self.webhooks.clone(),
         }
     }
 }
 
+impl<Evt> FromRef<MegaphoneState<Evt>> for WebhookDispatcher {
+    fn from_ref(app_state: &MegaphoneState<Evt>) -> Self {
+        app_state.webhooks.clone()
+    }
+}
+
 impl<Evt> FromRef<MegaphoneState<Evt>> for MegaphoneService<Evt> {
     fn from_ref(app_state: &MegaphoneState<Evt>) -> Self {
         app_state.megaphone_svc.clone()